@@ -1,26 +1,382 @@
-use std::{str::FromStr, sync::Arc};
+use crate::{lexer::Lexer, Error};
+use itertools::Itertools;
+use std::{
+    fmt::{Display, Formatter, Result as FmtResult},
+    str::FromStr,
+    sync::Arc,
+};
 
+/// A program, i.e. a sequence of top-level definitions, one of which is named `main`.
+#[derive(Debug, Eq, PartialEq)]
+pub struct Program {
+    /// The modules whose definitions are in scope in the program.
+    pub imports: Vec<Import>,
+
+    /// The definitions, in the order they appear in the source. Each is in scope in the ones
+    /// after it.
+    pub defs: Vec<Def>,
+}
+
+impl Program {
+    /// Desugars the program to a single expression, which binds each definition with a `let` (or a
+    /// `let rec`, for functions) and evaluates to `main`.
+    pub fn to_expr(&self) -> Result<Expr, Error> {
+        let main = self
+            .defs
+            .iter()
+            .rev()
+            .find(|def| def.name == "main")
+            .ok_or(Error::MissingMain)?;
+        let main = Expr::Var(main.name.clone(), main.span);
+        Ok(self.defs.iter().rev().fold(main, |e, def| def.bind(e)))
+    }
+}
+
+impl Display for Program {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        let imports = self.imports.iter().map(|import| import.to_string());
+        let defs = self.defs.iter().map(|def| def.to_string());
+        write!(fmt, "{}", imports.chain(defs).join("\n"))
+    }
+}
+
+impl FromStr for Program {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Program, Error> {
+        let mut lexer = Lexer::new(s);
+        let mut program = crate::grammar::ProgramParser::new()
+            .parse(&mut lexer)
+            .map_err(|err| Error::from_parse_error(err, s.len()))?;
+
+        // A comment on the same line as the end of an item is attached to it. Other comments are
+        // attached to the first item that ends after them, or the last item if there is none.
+        let spans = program
+            .imports
+            .iter()
+            .map(|import| import.span)
+            .chain(program.defs.iter().map(|def| def.span))
+            .collect::<Vec<_>>();
+        for comment in lexer.comments {
+            let next = spans
+                .iter()
+                .position(|span| span.end >= comment.span.end)
+                .unwrap_or(spans.len());
+            let index = match next.checked_sub(1) {
+                Some(prev) if !s[spans[prev].end..comment.span.start].contains('\n') => prev,
+                _ => next.min(spans.len() - 1),
+            };
+            if index < program.imports.len() {
+                program.imports[index].comments.push(comment);
+            } else {
+                program.defs[index - program.imports.len()]
+                    .comments
+                    .push(comment);
+            }
+        }
+        Ok(program)
+    }
+}
+
+/// A comment, including its delimiters.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Comment {
+    /// The text of the comment.
+    pub text: String,
+
+    /// The location of the comment.
+    pub span: Span,
+}
+
+/// An import of another module, i.e. another file containing a program.
 #[derive(Debug, Display, Eq, PartialEq)]
+#[display(fmt = "import {:?}", path)]
+pub struct Import {
+    /// The path to the file, relative to the directory containing the importing file.
+    pub path: String,
+
+    /// The location of the import.
+    pub span: Span,
+
+    /// The comments before and inside the import.
+    pub comments: Vec<Comment>,
+}
+
+/// A top-level definition. Definitions whose bodies are lambdas may refer to themselves.
+#[derive(Clone, Debug, Display, Eq, PartialEq)]
+#[display(
+    fmt = "def {}{} = {}",
+    name,
+    "ty.as_ref().map(|t| format!(\" : {}\", t)).unwrap_or_default()",
+    body
+)]
+pub struct Def {
+    /// The name being defined.
+    pub name: String,
+
+    /// The type the definition is annotated with, if any.
+    pub ty: Option<Arc<Type>>,
+
+    /// The body of the definition.
+    pub body: Expr,
+
+    /// The location of the whole definition.
+    pub span: Span,
+
+    /// The comments before and inside the definition.
+    pub comments: Vec<Comment>,
+}
+
+impl Def {
+    /// Binds the definition in an expression.
+    pub(crate) fn bind(&self, e: Expr) -> Expr {
+        let (name, ty, body) = (
+            self.name.clone(),
+            self.ty.clone(),
+            Box::new(self.body.clone()),
+        );
+        match self.body {
+            Expr::Lam(..) => Expr::LetRec(name, ty, body, Box::new(e), self.span),
+            _ => Expr::Let(name, ty, body, Box::new(e), self.span),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Display, Eq, PartialEq)]
 pub enum Expr {
     #[display(fmt = "({} {})", _0, _1)]
-    App(Box<Expr>, Box<Expr>),
+    App(Box<Expr>, Box<Expr>, Span),
+
+    #[display(fmt = "{}", _0)]
+    Bool(bool, Span),
+
+    #[display(fmt = "(case {} of inl {} => {} | inr {} => {})", _0, _1, _2, _3, _4)]
+    Case(Box<Expr>, String, Box<Expr>, String, Box<Expr>, Span),
+
+    #[display(fmt = "(fst {})", _0)]
+    Fst(Box<Expr>, Span),
 
-    #[display(fmt = "(λ{}:{}. {})", _0, _1, _2)]
-    Lam(String, Arc<Type>, Box<Expr>),
+    #[display(fmt = "(if {} then {} else {})", _0, _1, _2)]
+    If(Box<Expr>, Box<Expr>, Box<Expr>, Span),
+
+    #[display(
+        fmt = "(inl{} {})",
+        "_0.as_ref().map(|t| format!(\"[{}]\", t)).unwrap_or_default()",
+        _1
+    )]
+    Inl(Option<Arc<Type>>, Box<Expr>, Span),
+
+    #[display(
+        fmt = "(inr{} {})",
+        "_0.as_ref().map(|t| format!(\"[{}]\", t)).unwrap_or_default()",
+        _1
+    )]
+    Inr(Option<Arc<Type>>, Box<Expr>, Span),
+
+    #[display(
+        fmt = "(λ{}{}. {})",
+        _0,
+        "_1.as_ref().map(|t| format!(\":{}\", t)).unwrap_or_default()",
+        _2
+    )]
+    Lam(String, Option<Arc<Type>>, Box<Expr>, Span),
+
+    #[display(
+        fmt = "(let {}{} = {} in {})",
+        _0,
+        "_1.as_ref().map(|t| format!(\":{}\", t)).unwrap_or_default()",
+        _2,
+        _3
+    )]
+    Let(String, Option<Arc<Type>>, Box<Expr>, Box<Expr>, Span),
+
+    #[display(
+        fmt = "(let rec {}{} = {} in {})",
+        _0,
+        "_1.as_ref().map(|t| format!(\":{}\", t)).unwrap_or_default()",
+        _2,
+        _3
+    )]
+    LetRec(String, Option<Arc<Type>>, Box<Expr>, Box<Expr>, Span),
 
     #[display(fmt = "{}", _0)]
-    Lit(u32),
+    Lit(i64, Span),
+
+    #[display(fmt = "({}; {})", _0, _1)]
+    Seq(Box<Expr>, Box<Expr>, Span),
+
+    #[display(fmt = "(snd {})", _0)]
+    Snd(Box<Expr>, Span),
+
+    #[display(fmt = "({}, {})", _0, _1)]
+    Tuple(Box<Expr>, Box<Expr>, Span),
+
+    #[display(fmt = "({} [{}])", _0, _1)]
+    TyApp(Box<Expr>, Arc<Type>, Span),
+
+    #[display(fmt = "(Λ{}. {})", _0, _1)]
+    TyLam(String, Box<Expr>, Span),
+
+    #[display(fmt = "()")]
+    Unit(Span),
 
     #[display(fmt = "{}", _0)]
-    Var(String),
+    Var(String, Span),
+}
+
+impl Expr {
+    /// Returns the span of source code the expression was parsed from.
+    pub fn span(&self) -> Span {
+        match *self {
+            Expr::App(_, _, span)
+            | Expr::Bool(_, span)
+            | Expr::Case(_, _, _, _, _, span)
+            | Expr::Fst(_, span)
+            | Expr::If(_, _, _, span)
+            | Expr::Inl(_, _, span)
+            | Expr::Inr(_, _, span)
+            | Expr::Lam(_, _, _, span)
+            | Expr::Let(_, _, _, _, span)
+            | Expr::LetRec(_, _, _, _, span)
+            | Expr::Lit(_, span)
+            | Expr::Seq(_, _, span)
+            | Expr::Snd(_, span)
+            | Expr::Tuple(_, _, span)
+            | Expr::TyApp(_, _, span)
+            | Expr::TyLam(_, _, span)
+            | Expr::Unit(span)
+            | Expr::Var(_, span) => span,
+        }
+    }
+
+    /// Strips any type abstractions and applications from the root of the expression. Types are
+    /// erased before names are removed, since they have no runtime representation.
+    pub(crate) fn erase(&self) -> &Expr {
+        match self {
+            Expr::TyApp(e, _, _) | Expr::TyLam(_, e, _) => e.erase(),
+            e => e,
+        }
+    }
+
+    /// Returns the integer literals in the expression, along with their locations.
+    pub(crate) fn literals(&self) -> Vec<(i64, Span)> {
+        match *self {
+            Expr::Lit(n, span) => vec![(n, span)],
+            Expr::Bool(..) | Expr::Unit(_) | Expr::Var(..) => Vec::new(),
+            Expr::Fst(ref e, _)
+            | Expr::Inl(_, ref e, _)
+            | Expr::Inr(_, ref e, _)
+            | Expr::Lam(_, _, ref e, _)
+            | Expr::Snd(ref e, _)
+            | Expr::TyApp(ref e, _, _)
+            | Expr::TyLam(_, ref e, _) => e.literals(),
+            Expr::App(ref l, ref r, _)
+            | Expr::Let(_, _, ref l, ref r, _)
+            | Expr::LetRec(_, _, ref l, ref r, _)
+            | Expr::Seq(ref l, ref r, _)
+            | Expr::Tuple(ref l, ref r, _) => {
+                let mut lits = l.literals();
+                lits.extend(r.literals());
+                lits
+            }
+            Expr::Case(ref e, _, ref a, _, ref b, _) | Expr::If(ref e, ref a, ref b, _) => {
+                let mut lits = e.literals();
+                lits.extend(a.literals());
+                lits.extend(b.literals());
+                lits
+            }
+        }
+    }
+
+    /// Renames the free variables of the expression. `rename` returns the new name of a variable,
+    /// or `None` to leave it as is.
+    pub(crate) fn rename_free(&self, rename: &impl Fn(&str) -> Option<String>) -> Expr {
+        self.rename_free_in(&mut Vec::new(), rename)
+    }
+
+    fn rename_free_in<'e>(
+        &'e self,
+        scope: &mut Vec<&'e str>,
+        rename: &impl Fn(&str) -> Option<String>,
+    ) -> Expr {
+        let go = |e: &'e Expr, scope: &mut Vec<&'e str>| Box::new(e.rename_free_in(scope, rename));
+        let bound = |x: &'e str, e: &'e Expr, scope: &mut Vec<&'e str>| {
+            scope.push(x);
+            let e = Box::new(e.rename_free_in(scope, rename));
+            scope.pop();
+            e
+        };
+        match *self {
+            Expr::App(ref l, ref a, span) => Expr::App(go(l, scope), go(a, scope), span),
+            Expr::Bool(b, span) => Expr::Bool(b, span),
+            Expr::Case(ref e, ref x, ref a, ref y, ref b, span) => Expr::Case(
+                go(e, scope),
+                x.clone(),
+                bound(x, a, scope),
+                y.clone(),
+                bound(y, b, scope),
+                span,
+            ),
+            Expr::Fst(ref e, span) => Expr::Fst(go(e, scope), span),
+            Expr::If(ref c, ref a, ref b, span) => {
+                Expr::If(go(c, scope), go(a, scope), go(b, scope), span)
+            }
+            Expr::Inl(ref ty, ref e, span) => Expr::Inl(ty.clone(), go(e, scope), span),
+            Expr::Inr(ref ty, ref e, span) => Expr::Inr(ty.clone(), go(e, scope), span),
+            Expr::Lam(ref x, ref ty, ref b, span) => {
+                Expr::Lam(x.clone(), ty.clone(), bound(x, b, scope), span)
+            }
+            Expr::Let(ref x, ref ty, ref e1, ref e2, span) => Expr::Let(
+                x.clone(),
+                ty.clone(),
+                go(e1, scope),
+                bound(x, e2, scope),
+                span,
+            ),
+            Expr::LetRec(ref x, ref ty, ref e1, ref e2, span) => Expr::LetRec(
+                x.clone(),
+                ty.clone(),
+                bound(x, e1, scope),
+                bound(x, e2, scope),
+                span,
+            ),
+            Expr::Lit(n, span) => Expr::Lit(n, span),
+            Expr::Seq(ref e1, ref e2, span) => Expr::Seq(go(e1, scope), go(e2, scope), span),
+            Expr::Snd(ref e, span) => Expr::Snd(go(e, scope), span),
+            Expr::Tuple(ref l, ref r, span) => Expr::Tuple(go(l, scope), go(r, scope), span),
+            Expr::TyApp(ref e, ref ty, span) => Expr::TyApp(go(e, scope), ty.clone(), span),
+            Expr::TyLam(ref a, ref e, span) => Expr::TyLam(a.clone(), go(e, scope), span),
+            Expr::Unit(span) => Expr::Unit(span),
+            Expr::Var(ref n, span) => match rename(n) {
+                Some(name) if !scope.contains(&&**n) => Expr::Var(name, span),
+                _ => Expr::Var(n.clone(), span),
+            },
+        }
+    }
 }
 
 impl FromStr for Expr {
-    type Err = String;
-    fn from_str(s: &str) -> Result<Expr, String> {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Expr, Error> {
         crate::grammar::ExprParser::new()
-            .parse(s)
-            .map_err(|err| err.to_string())
+            .parse(Lexer::new(s))
+            .map_err(|err| Error::from_parse_error(err, s.len()))
+    }
+}
+
+/// A range of bytes in the source code.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Span {
+    /// The byte offset of the start of the range.
+    pub start: usize,
+
+    /// The byte offset of the end of the range, exclusive.
+    pub end: usize,
+}
+
+impl Span {
+    /// Creates a span from the start and end byte offsets.
+    pub fn new(start: usize, end: usize) -> Span {
+        Span { start, end }
     }
 }
 
@@ -28,15 +384,39 @@ impl FromStr for Expr {
 pub enum Type {
     #[display(fmt = "({}) -> {}", _0, _1)]
     Arr(Arc<Type>, Arc<Type>),
+    #[display(fmt = "bool")]
+    Bool,
+    #[display(fmt = "forall {}. {}", _0, _1)]
+    Forall(String, Arc<Type>),
     #[display(fmt = "int")]
     Int,
+    #[display(fmt = "{}", _0)]
+    Param(String),
+    #[display(fmt = "({}) * ({})", _0, _1)]
+    Prod(Arc<Type>, Arc<Type>),
+    #[display(fmt = "({}) + ({})", _0, _1)]
+    Sum(Arc<Type>, Arc<Type>),
+    #[display(fmt = "unit")]
+    Unit,
+    #[display(fmt = "'{}", "type_var_name(*_0)")]
+    Var(usize),
+}
+
+/// Returns the name a type variable is displayed with: `a` through `z`, then `a1` through `z1`, and
+/// so on.
+fn type_var_name(n: usize) -> String {
+    let letter = (b'a' + (n % 26) as u8) as char;
+    match n / 26 {
+        0 => letter.to_string(),
+        suffix => format!("{}{}", letter, suffix),
+    }
 }
 
 impl FromStr for Type {
-    type Err = String;
-    fn from_str(s: &str) -> Result<Type, String> {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Type, Error> {
         crate::grammar::TypeParser::new()
-            .parse(s)
-            .map_err(|err| err.to_string())
+            .parse(Lexer::new(s))
+            .map_err(|err| Error::from_parse_error(err, s.len()))
     }
 }
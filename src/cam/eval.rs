@@ -1,4 +1,6 @@
-use crate::cam::StaticCombinator;
+use crate::cam::{binop, cmpop, StaticCombinator};
+use itertools::Itertools;
+use std::io::{stdout, Write};
 
 /// A static or dynamic combinator. This representation is for implementing evaluation by term
 /// rewriting.
@@ -8,10 +10,27 @@ pub enum Combinator {
     #[display(fmt = "App")]
     App,
 
+    /// The `Bind` combinator. `Bind(x)z = (z, xz)`
+    #[display(fmt = "Bind({})", _0)]
+    Bind(Box<Combinator>),
+
     /// Applies a combinator to another.
     #[display(fmt = "({} {})", _0, _1)]
     Apply(Box<Combinator>, Box<Combinator>),
 
+    /// A bare boolean.
+    #[display(fmt = "{}", _0)]
+    Bool(bool),
+
+    /// The `Branch` combinator. `Branch(x, y)(z, true) = xz` and `Branch(x, y)(z, false) = yz`
+    #[display(fmt = "Branch({}, {})", _0, _1)]
+    Branch(Box<Combinator>, Box<Combinator>),
+
+    /// The `Case` combinator. `Case(x, y)(z, inl v) = x(z, v)` and
+    /// `Case(x, y)(z, inr v) = y(z, v)`
+    #[display(fmt = "Case({}, {})", _0, _1)]
+    Case(Box<Combinator>, Box<Combinator>),
+
     /// The composition combinator. `(x∘y)z = x(yz)`
     #[display(fmt = "({} ∘ {})", _0, _1)]
     Com(Box<Combinator>, Box<Combinator>),
@@ -24,17 +43,33 @@ pub enum Combinator {
     #[display(fmt = "Fst")]
     Fst,
 
+    /// The identity combinator. `Id x = x`
+    #[display(fmt = "Id")]
+    Id,
+
+    /// The `Inl` combinator. `Inl x = inl x`
+    #[display(fmt = "Inl")]
+    Inl,
+
+    /// The `Inr` combinator. `Inr x = inr x`
+    #[display(fmt = "Inr")]
+    Inr,
+
     /// The `Λ` combinator. `App(Λ(x)y, z) = x(y, z)`
     #[display(fmt = "Λ({})", _0)]
     Lam(Box<Combinator>),
 
+    /// A value tagged as the left alternative of a sum.
+    #[display(fmt = "inl {}", _0)]
+    Left(Box<Combinator>),
+
     /// A bare name.
     #[display(fmt = "{}", _0)]
     Name(String),
 
     /// A bare number.
     #[display(fmt = "{}", _0)]
-    Num(u32),
+    Num(i64),
 
     /// The `<,>` combinator. `<x, y>z = (xz, yz)`
     #[display(fmt = "<{}, {}>", _0, _1)]
@@ -44,28 +79,67 @@ pub enum Combinator {
     #[display(fmt = "'{}", _0)]
     Quote(Box<Combinator>),
 
+    /// A value tagged as the right alternative of a sum.
+    #[display(fmt = "inr {}", _0)]
+    Right(Box<Combinator>),
+
+    /// The `Rec` combinator. `Rec(x)z = w`, where `w = (z, Λ(x)w)`
+    #[display(fmt = "Rec({})", _0)]
+    Rec(Box<Combinator>),
+
+    /// The cyclic environment `w = (z, Λ(x)w)` built by `Rec(x)z`. This is unfolded lazily by `Fst`
+    /// and `Snd`, so it can be represented finitely.
+    #[display(fmt = "μw.({1}, Λ({0})w)", _0, _1)]
+    RecEnv(Box<Combinator>, Box<Combinator>),
+
     /// The `Snd` combinator. `Snd(x, y) = y`
     #[display(fmt = "Snd")]
     Snd,
+
+    /// The sequencing combinator. `Then(x, y)z = yz`, but `xz` is evaluated first for its effects.
+    #[display(fmt = "Then({}, {})", _0, _1)]
+    Then(Box<Combinator>, Box<Combinator>),
+
+    /// The bare unit value.
+    #[display(fmt = "()")]
+    Unit,
 }
 
 impl Combinator {
+    /// Returns whether the combinator is a value, i.e. whether it is in normal form when applied
+    /// to nothing. Static combinators are values, as are numbers, names, pairs of values, closures
+    /// over values, and builtins partially applied to values.
+    pub fn is_value(&self) -> bool {
+        match *self {
+            Combinator::Apply(ref l, ref r) => match **l {
+                Combinator::Lam(_) | Combinator::Name(_) => r.is_value(),
+                _ => false,
+            },
+            Combinator::DPair(ref l, ref r) => l.is_value() && r.is_value(),
+            _ => true,
+        }
+    }
+
     /// Attempts to evaluate the expression by "a few" steps. If the value is not actually modified,
     /// the evaluation has completed.
+    ///
+    /// Evaluation is call-by-value: with the exception of `App`, which neither duplicates nor
+    /// discards its argument, a rule only fires once the combinator's argument is a value. Pairs
+    /// are evaluated left-to-right.
+    ///
+    /// Output from `print` is written to stdout.
     pub fn eval_step(self) -> Combinator {
+        self.eval_step_with(&mut stdout())
+    }
+
+    /// Like `eval_step`, but output from `print` is written to the given sink.
+    pub fn eval_step_with(self, out: &mut dyn Write) -> Combinator {
         match self {
-            Combinator::Apply(l, r) => match (l.eval_step(), r.eval_step()) {
-                (Combinator::Quote(x), _) => *x, // 'xy = x
-                (Combinator::Com(ll, lr), r) => {
-                    Combinator::Apply(ll, Box::new(Combinator::Apply(lr, Box::new(r)))) // (x∘y)z = x(yz)
-                }
-                (Combinator::Pair(ll, lr), r) => {
-                    // <x, y>z = (xz, yz)
-                    let r = Box::new(r);
-                    Combinator::DPair(
-                        Box::new(Combinator::Apply(ll, r.clone())),
-                        Box::new(Combinator::Apply(lr, r)),
-                    )
+            Combinator::Apply(l, r) => match (l.eval_step_with(out), r.eval_step_with(out)) {
+                (Combinator::App, Combinator::DPair(rl, rr))
+                    if rl.is_builtin() && rr.is_value() =>
+                {
+                    apply_builtin(*rl, *rr, out)
                 }
                 (Combinator::App, Combinator::DPair(rl, rr)) => match *rl {
                     Combinator::Apply(rll, rlr) => match *rll {
@@ -86,58 +160,219 @@ impl Combinator {
                         Box::new(Combinator::DPair(Box::new(rl), rr)),
                     ),
                 },
+                (l, r) if !r.is_value() => Combinator::Apply(Box::new(l), Box::new(r)),
+                (Combinator::Quote(x), _) => *x, // 'xy = x
+                (Combinator::Bind(x), z) => {
+                    // Bind(x)z = (z, xz)
+                    let z = Box::new(z);
+                    Combinator::DPair(z.clone(), Box::new(Combinator::Apply(x, z)))
+                }
+                (Combinator::Branch(x, y), Combinator::DPair(z, c)) => match *c {
+                    // Branch(x, y)(z, true) = xz
+                    Combinator::Bool(true) => Combinator::Apply(x, z),
+                    // Branch(x, y)(z, false) = yz
+                    Combinator::Bool(false) => Combinator::Apply(y, z),
+                    c => Combinator::Apply(
+                        Box::new(Combinator::Branch(x, y)),
+                        Box::new(Combinator::DPair(z, Box::new(c))),
+                    ),
+                },
+                (Combinator::Id, x) => x, // Id x = x
+                (Combinator::Inl, x) => Combinator::Left(Box::new(x)), // Inl x = inl x
+                (Combinator::Inr, x) => Combinator::Right(Box::new(x)), // Inr x = inr x
+                (Combinator::Case(x, y), Combinator::DPair(z, s)) => match *s {
+                    // Case(x, y)(z, inl v) = x(z, v)
+                    Combinator::Left(v) => Combinator::Apply(x, Box::new(Combinator::DPair(z, v))),
+                    // Case(x, y)(z, inr v) = y(z, v)
+                    Combinator::Right(v) => Combinator::Apply(y, Box::new(Combinator::DPair(z, v))),
+                    s => Combinator::Apply(
+                        Box::new(Combinator::Case(x, y)),
+                        Box::new(Combinator::DPair(z, Box::new(s))),
+                    ),
+                },
+                (Combinator::Com(ll, lr), r) => {
+                    // (x∘y)z = x(yz)
+                    Combinator::Apply(ll, Box::new(Combinator::Apply(lr, Box::new(r))))
+                }
+                (Combinator::Pair(ll, lr), r) => {
+                    // <x, y>z = (xz, yz)
+                    let r = Box::new(r);
+                    Combinator::DPair(
+                        Box::new(Combinator::Apply(ll, r.clone())),
+                        Box::new(Combinator::Apply(lr, r)),
+                    )
+                }
+                (Combinator::Then(x, y), z) => {
+                    // Then(x, y)z = (y∘Fst)(z, xz), which forces xz before discarding it.
+                    let z = Box::new(z);
+                    Combinator::Apply(
+                        Box::new(Combinator::Com(y, Box::new(Combinator::Fst))),
+                        Box::new(Combinator::DPair(
+                            z.clone(),
+                            Box::new(Combinator::Apply(x, z)),
+                        )),
+                    )
+                }
                 (Combinator::Fst, Combinator::DPair(x, _)) => *x, // Fst(x, y) = x
                 (Combinator::Snd, Combinator::DPair(_, x)) => *x, // Snd(x, y) = y
+                (Combinator::Rec(x), z) => Combinator::RecEnv(x, Box::new(z)),
+                (Combinator::Fst, Combinator::RecEnv(_, z)) => *z,
+                (Combinator::Snd, Combinator::RecEnv(x, z)) => Combinator::Apply(
+                    Box::new(Combinator::Lam(x.clone())),
+                    Box::new(Combinator::RecEnv(x, z)),
+                ),
                 (l, r) => Combinator::Apply(Box::new(l), Box::new(r)),
             },
+            Combinator::DPair(l, r) => {
+                if l.is_value() {
+                    Combinator::DPair(l, Box::new(r.eval_step_with(out)))
+                } else {
+                    Combinator::DPair(Box::new(l.eval_step_with(out)), r)
+                }
+            }
 
             // The rest of the cases (and the uncommented ones above) are all simply recursing down
             // to evaluate lower in the tree.
-            Combinator::Com(l, r) => {
-                Combinator::Com(Box::new(l.eval_step()), Box::new(r.eval_step()))
-            }
-            Combinator::DPair(l, r) => {
-                Combinator::DPair(Box::new(l.eval_step()), Box::new(r.eval_step()))
-            }
-            Combinator::Pair(l, r) => {
-                Combinator::Pair(Box::new(l.eval_step()), Box::new(r.eval_step()))
-            }
-            Combinator::Lam(c) => Combinator::Lam(Box::new(c.eval_step())),
-            Combinator::Quote(c) => Combinator::Quote(Box::new(c.eval_step())),
+            Combinator::Bind(x) => Combinator::Bind(Box::new(x.eval_step_with(out))),
+            Combinator::Branch(x, y) => Combinator::Branch(
+                Box::new(x.eval_step_with(out)),
+                Box::new(y.eval_step_with(out)),
+            ),
+            Combinator::Case(x, y) => Combinator::Case(
+                Box::new(x.eval_step_with(out)),
+                Box::new(y.eval_step_with(out)),
+            ),
+            Combinator::Com(l, r) => Combinator::Com(
+                Box::new(l.eval_step_with(out)),
+                Box::new(r.eval_step_with(out)),
+            ),
+            Combinator::Pair(l, r) => Combinator::Pair(
+                Box::new(l.eval_step_with(out)),
+                Box::new(r.eval_step_with(out)),
+            ),
+            Combinator::Lam(c) => Combinator::Lam(Box::new(c.eval_step_with(out))),
+            Combinator::Rec(c) => Combinator::Rec(Box::new(c.eval_step_with(out))),
+            Combinator::RecEnv(x, z) => Combinator::RecEnv(x, z),
+            Combinator::Quote(c) => Combinator::Quote(Box::new(c.eval_step_with(out))),
+            Combinator::Then(x, y) => Combinator::Then(
+                Box::new(x.eval_step_with(out)),
+                Box::new(y.eval_step_with(out)),
+            ),
             Combinator::App => Combinator::App,
+            Combinator::Bool(b) => Combinator::Bool(b),
             Combinator::Fst => Combinator::Fst,
+            Combinator::Id => Combinator::Id,
+            Combinator::Inl => Combinator::Inl,
+            Combinator::Inr => Combinator::Inr,
+            Combinator::Left(x) => Combinator::Left(x),
+            Combinator::Right(x) => Combinator::Right(x),
             Combinator::Name(n) => Combinator::Name(n),
             Combinator::Num(n) => Combinator::Num(n),
             Combinator::Snd => Combinator::Snd,
+            Combinator::Unit => Combinator::Unit,
         }
     }
 
-    /// Evaluates a combinator expression fully.
-    pub fn eval(mut self) -> Combinator {
+    /// Evaluates a combinator expression fully. Output from `print` is written to stdout.
+    pub fn eval(self) -> Combinator {
+        self.eval_with(&mut stdout())
+    }
+
+    /// Like `eval`, but output from `print` is written to the given sink.
+    pub fn eval_with(mut self, out: &mut dyn Write) -> Combinator {
         // App is a safe constant to make this a do-while -- if the combinator expression is just
         // App (and the loop is skipped as a result), the expression was irreducible anyway.
         loop {
             let last = self.clone();
-            self = self.eval_step();
+            self = self.eval_step_with(out);
             if self == last {
                 break;
             }
         }
         self
     }
+
+    /// Returns whether the combinator is a builtin, or a builtin partially applied to a value.
+    fn is_builtin(&self) -> bool {
+        match *self {
+            Combinator::Name(_) => true,
+            Combinator::Apply(ref l, ref r) => match **l {
+                Combinator::Name(_) => r.is_value(),
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+}
+
+/// Applies a builtin (possibly partially applied already) to a value. If the application is
+/// ill-typed or undefined (e.g. division by zero), the `App` is left unreduced.
+fn apply_builtin(f: Combinator, x: Combinator, out: &mut dyn Write) -> Combinator {
+    match (f, x) {
+        (Combinator::Name(ref n), Combinator::Num(x)) if n == "print" => {
+            writeln!(out, "{}", x).expect("failed to write output");
+            Combinator::Unit
+        }
+        (Combinator::Name(n), x) => Combinator::Apply(Box::new(Combinator::Name(n)), Box::new(x)),
+        (Combinator::Apply(n, l), Combinator::Num(r)) => {
+            let result = match (&*n, &*l) {
+                (Combinator::Name(n), &Combinator::Num(l)) => binop(n, l, r)
+                    .map(Combinator::Num)
+                    .or_else(|| cmpop(n, l, r).map(Combinator::Bool)),
+                _ => None,
+            };
+            result.unwrap_or_else(|| stuck_app(Combinator::Apply(n, l), Combinator::Num(r)))
+        }
+        (f, x) => stuck_app(f, x),
+    }
+}
+
+/// Builds the irreducible term `App(f, x)`.
+fn stuck_app(f: Combinator, x: Combinator) -> Combinator {
+    Combinator::Apply(
+        Box::new(Combinator::App),
+        Box::new(Combinator::DPair(Box::new(f), Box::new(x))),
+    )
 }
 
 impl From<StaticCombinator> for Combinator {
     fn from(c: StaticCombinator) -> Combinator {
         match c {
+            // Access(n) = Snd∘Fst∘...∘Fst
+            StaticCombinator::Access(n) => (0..n).fold(Combinator::Snd, |c, _| {
+                Combinator::Com(Box::new(c), Box::new(Combinator::Fst))
+            }),
             StaticCombinator::App => Combinator::App,
+            StaticCombinator::Bind(x) => Combinator::Bind(x.into()),
+            StaticCombinator::Branch(x, y) => Combinator::Branch(x.into(), y.into()),
+            StaticCombinator::Case(x, y) => Combinator::Case(x.into(), y.into()),
             StaticCombinator::Com(l, r) => Combinator::Com(l.into(), r.into()),
             StaticCombinator::Fst => Combinator::Fst,
+            StaticCombinator::Id => Combinator::Id,
+            StaticCombinator::Inl => Combinator::Inl,
+            StaticCombinator::Inr => Combinator::Inr,
             StaticCombinator::Lam(b) => Combinator::Lam(b.into()),
             StaticCombinator::Pair(l, r) => Combinator::Pair(l.into(), r.into()),
+            StaticCombinator::QuoteBool(b) => Combinator::Quote(Box::new(Combinator::Bool(b))),
+            StaticCombinator::QuoteDef(m, n) => {
+                unreachable!(
+                    "definitions are bound by lets before evaluation: {}.{}",
+                    m, n
+                )
+            }
             StaticCombinator::QuoteName(n) => Combinator::Quote(Box::new(Combinator::Name(n))),
             StaticCombinator::QuoteNum(n) => Combinator::Quote(Box::new(Combinator::Num(n))),
+            StaticCombinator::QuoteUnit => Combinator::Quote(Box::new(Combinator::Unit)),
+            StaticCombinator::Rec(x) => Combinator::Rec(x.into()),
+            // Seq[x, y, z] = x∘(y∘z)
+            StaticCombinator::Seq(cs) => cs
+                .into_iter()
+                .rev()
+                .map(Combinator::from)
+                .fold1(|r, l| Combinator::Com(Box::new(l), Box::new(r)))
+                .unwrap_or(Combinator::Id),
             StaticCombinator::Snd => Combinator::Snd,
+            StaticCombinator::Then(x, y) => Combinator::Then(x.into(), y.into()),
         }
     }
 }
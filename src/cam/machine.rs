@@ -0,0 +1,448 @@
+//! The Categorical Abstract Machine, as described by Cousineau, Curien, and Mauny.
+//!
+//! The machine has a term register, a stack of terms, and a code pointer into a flat list of
+//! instructions. This is the semantic reference for the Forth backend: each instruction
+//! corresponds to the words `compile_to_forth` emits for the same combinator.
+
+use crate::{
+    ast::Expr,
+    cam::{binop, cmpop, StaticCombinator},
+    Error,
+};
+use std::{
+    io::{stdout, Write},
+    mem::{replace, swap},
+    rc::Rc,
+};
+
+/// An instruction of the CAM.
+#[derive(Clone, Debug, Display, PartialEq)]
+pub enum Instruction {
+    /// Replaces the term, which must be an environment, with the variable at the given index in it.
+    #[display(fmt = "acc {}", _0)]
+    Access(usize),
+
+    /// Applies the closure in the first component of the term to the second component.
+    #[display(fmt = "app")]
+    App,
+
+    /// Replaces the term, which must be a pair of a value and a boolean, with the value. If the
+    /// boolean is false, jumps to the given address.
+    #[display(fmt = "branch {}", _0)]
+    Branch(usize),
+
+    /// Replaces the term, which must be a pair of a value and a tagged value, with the pair of the
+    /// value and the contents of the tagged value. If the tag is `inr`, jumps to the given address.
+    #[display(fmt = "case {}", _0)]
+    Case(usize),
+
+    /// Replaces the term with its first component.
+    #[display(fmt = "car")]
+    Car,
+
+    /// Replaces the term with its second component.
+    #[display(fmt = "cdr")]
+    Cdr,
+
+    /// Pops a value off the stack, and replaces the term with the pair of that value and the term.
+    #[display(fmt = "cons")]
+    Cons,
+
+    /// Replaces the term with a closure of the code at the given address over the term.
+    #[display(fmt = "cur {}", _0)]
+    Cur(usize),
+
+    /// Tags the term as the left alternative of a sum.
+    #[display(fmt = "inl")]
+    Inl,
+
+    /// Tags the term as the right alternative of a sum.
+    #[display(fmt = "inr")]
+    Inr,
+
+    /// Jumps to the given address.
+    #[display(fmt = "jump {}", _0)]
+    Jump(usize),
+
+    /// Pops a value off the stack into the term, discarding the term.
+    #[display(fmt = "pop")]
+    Pop,
+
+    /// Pushes the term onto the stack.
+    #[display(fmt = "push")]
+    Push,
+
+    /// Replaces the term with a constant.
+    #[display(fmt = "quote {}", _0)]
+    Quote(Value),
+
+    /// Replaces the term `z` with the environment `w = (z, Λ(x)w)`, where `x` is the code at the
+    /// given address.
+    #[display(fmt = "rec {}", _0)]
+    Rec(usize),
+
+    /// Returns from the current closure, or halts if there is no closure to return from.
+    #[display(fmt = "return")]
+    Return,
+
+    /// Swaps the term with the top of the stack.
+    #[display(fmt = "swap")]
+    Swap,
+}
+
+/// A value the CAM operates on.
+#[derive(Clone, Debug, Display, PartialEq)]
+pub enum Value {
+    /// A boolean.
+    #[display(fmt = "{}", _0)]
+    Bool(bool),
+
+    /// A builtin function, with the arguments it has been applied to so far.
+    #[display(fmt = "{}", _0)]
+    Builtin(String, Vec<Value>),
+
+    /// A closure, made of the address of its code and the environment it closes over.
+    #[display(fmt = "<closure {}>", _0)]
+    Closure(usize, Rc<Value>),
+
+    /// A value tagged as the left alternative of a sum.
+    #[display(fmt = "inl {}", _0)]
+    Inl(Rc<Value>),
+
+    /// A value tagged as the right alternative of a sum.
+    #[display(fmt = "inr {}", _0)]
+    Inr(Rc<Value>),
+
+    /// A number.
+    #[display(fmt = "{}", _0)]
+    Num(i64),
+
+    /// A pair of values.
+    #[display(fmt = "({}, {})", _0, _1)]
+    Pair(Rc<Value>, Rc<Value>),
+
+    /// The unit value.
+    #[display(fmt = "()")]
+    Unit,
+
+    /// The environment `w = (z, Λ(x)w)` built by the `rec` instruction, made of `z` and the address
+    /// of `x`. Rather than being cyclic, the closure is rebuilt each time it is accessed.
+    #[display(fmt = "({}, <closure {}>)", _0, _1)]
+    RecEnv(Rc<Value>, usize),
+}
+
+/// The state of a CAM.
+#[derive(Debug)]
+pub struct Machine {
+    code: Vec<Instruction>,
+    pc: usize,
+    returns: Vec<usize>,
+    stack: Vec<Value>,
+    term: Value,
+    allocations: usize,
+}
+
+impl Machine {
+    /// Creates a machine that will run the given code, starting from the first instruction, with
+    /// the given value as the initial term.
+    pub fn new(code: Vec<Instruction>, term: Value) -> Machine {
+        Machine {
+            code,
+            pc: 0,
+            returns: Vec::new(),
+            stack: Vec::new(),
+            term,
+            allocations: 0,
+        }
+    }
+
+    /// Runs the machine until it halts, returning the final term. Output from `print` is written
+    /// to stdout.
+    pub fn run(&mut self) -> Result<Value, Error> {
+        self.run_with(&mut stdout())
+    }
+
+    /// Like `run`, but output from `print` is written to the given sink.
+    pub fn run_with(&mut self, out: &mut dyn Write) -> Result<Value, Error> {
+        while self.step(out)? {}
+        Ok(self.term.clone())
+    }
+
+    /// Returns the number of pairs allocated so far. Since closures, sums, and builtins are pairs
+    /// in the Forth runtime too, this is the number of pairs the Forth code compiled from the same
+    /// expression allocates. A program with top-level definitions is run as a chain of `let`s,
+    /// which allocates one more pair than the Forth code for each definition that is not a
+    /// function, to bind it in the environment; the Forth code stores it in a variable instead.
+    pub fn allocations(&self) -> usize {
+        self.allocations
+    }
+
+    /// Executes a single instruction. Returns whether the machine is still running.
+    pub fn step(&mut self, out: &mut dyn Write) -> Result<bool, Error> {
+        let instr = self
+            .code
+            .get(self.pc)
+            .cloned()
+            .ok_or_else(|| Error::Stuck(format!("Jumped out of code: {}", self.pc)))?;
+        self.pc += 1;
+        match instr {
+            Instruction::Access(n) => {
+                for _ in 0..n {
+                    self.term = self.take_pair()?.0;
+                }
+                self.term = self.take_pair()?.1;
+            }
+            Instruction::App => {
+                let (f, x) = self.take_pair()?;
+                match f {
+                    Value::Builtin(name, mut args) => {
+                        args.push(x);
+                        self.term = apply_builtin(name, args, out)?;
+                        // Partially applied builtins are closures over their first argument.
+                        if let Value::Builtin(..) = self.term {
+                            self.allocations += 1;
+                        }
+                        self.allocations += 1;
+                    }
+                    Value::Closure(addr, env) => {
+                        self.allocations += 1;
+                        self.returns.push(self.pc);
+                        self.pc = addr;
+                        self.term = Value::Pair(env, Rc::new(x));
+                    }
+                    f => return Err(Error::Stuck(format!("Not a function: {}", f))),
+                }
+            }
+            Instruction::Branch(addr) => {
+                let (z, c) = self.take_pair()?;
+                match c {
+                    Value::Bool(true) => {}
+                    Value::Bool(false) => self.pc = addr,
+                    c => return Err(Error::Stuck(format!("Not a boolean: {}", c))),
+                }
+                self.term = z;
+            }
+            Instruction::Case(addr) => {
+                let (z, s) = self.take_pair()?;
+                let v = match s {
+                    Value::Inl(v) => v,
+                    Value::Inr(v) => {
+                        self.pc = addr;
+                        v
+                    }
+                    s => return Err(Error::Stuck(format!("Not a sum: {}", s))),
+                };
+                self.allocations += 1;
+                self.term = Value::Pair(Rc::new(z), v);
+            }
+            Instruction::Car => self.term = self.take_pair()?.0,
+            Instruction::Cdr => self.term = self.take_pair()?.1,
+            Instruction::Cons => {
+                let l = self.pop()?;
+                let r = replace(&mut self.term, Value::Num(0));
+                self.term = Value::Pair(Rc::new(l), Rc::new(r));
+                self.allocations += 1;
+            }
+            Instruction::Cur(addr) => {
+                let env = replace(&mut self.term, Value::Num(0));
+                self.term = Value::Closure(addr, Rc::new(env));
+                self.allocations += 1;
+            }
+            Instruction::Inl => {
+                let v = replace(&mut self.term, Value::Num(0));
+                self.term = Value::Inl(Rc::new(v));
+                self.allocations += 1;
+            }
+            Instruction::Inr => {
+                let v = replace(&mut self.term, Value::Num(0));
+                self.term = Value::Inr(Rc::new(v));
+                self.allocations += 1;
+            }
+            Instruction::Jump(addr) => self.pc = addr,
+            Instruction::Pop => self.term = self.pop()?,
+            Instruction::Push => self.stack.push(self.term.clone()),
+            Instruction::Quote(v) => {
+                // Builtins are closures.
+                if let Value::Builtin(..) = v {
+                    self.allocations += 1;
+                }
+                self.term = v;
+            }
+            Instruction::Rec(addr) => {
+                let z = replace(&mut self.term, Value::Num(0));
+                self.term = Value::RecEnv(Rc::new(z), addr);
+                // The environment and the closure in it.
+                self.allocations += 2;
+            }
+            Instruction::Return => match self.returns.pop() {
+                Some(addr) => self.pc = addr,
+                None => return Ok(false),
+            },
+            Instruction::Swap => {
+                let top = self
+                    .stack
+                    .last_mut()
+                    .ok_or_else(|| Error::Stuck("Stack underflow".to_string()))?;
+                swap(top, &mut self.term);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Pops a value off the stack.
+    fn pop(&mut self) -> Result<Value, Error> {
+        self.stack
+            .pop()
+            .ok_or_else(|| Error::Stuck("Stack underflow".to_string()))
+    }
+
+    /// Takes the components of the term, which must be a pair.
+    fn take_pair(&mut self) -> Result<(Value, Value), Error> {
+        match replace(&mut self.term, Value::Num(0)) {
+            Value::Pair(l, r) => Ok((unwrap_rc(l), unwrap_rc(r))),
+            Value::RecEnv(z, addr) => {
+                let w = Value::RecEnv(z.clone(), addr);
+                Ok((unwrap_rc(z), Value::Closure(addr, Rc::new(w))))
+            }
+            term => Err(Error::Stuck(format!("Not a pair: {}", term))),
+        }
+    }
+}
+
+/// Applies a builtin to the arguments it has been given, if it has been given enough.
+fn apply_builtin(name: String, args: Vec<Value>, out: &mut dyn Write) -> Result<Value, Error> {
+    match (&*name, &*args) {
+        ("print", &[Value::Num(n)]) => {
+            writeln!(out, "{}", n)?;
+            Ok(Value::Unit)
+        }
+        (_, &[_]) if name != "print" => Ok(Value::Builtin(name, args)),
+        (_, &[Value::Num(_), Value::Num(0)]) if name == "/" || name == "mod" => {
+            Err(Error::DivisionByZero)
+        }
+        (_, &[Value::Num(l), Value::Num(r)]) => binop(&name, l, r)
+            .map(Value::Num)
+            .or_else(|| cmpop(&name, l, r).map(Value::Bool))
+            .ok_or_else(|| Error::Stuck(format!("Cannot evaluate: {} {} {}", name, l, r))),
+        _ => Err(Error::Stuck(format!(
+            "Cannot apply {} to {}",
+            name,
+            itertools::join(&args, ", ")
+        ))),
+    }
+}
+
+/// Gets a value out of an `Rc`, cloning it only if it is shared.
+fn unwrap_rc(rc: Rc<Value>) -> Value {
+    Rc::try_unwrap(rc).unwrap_or_else(|rc| (*rc).clone())
+}
+
+impl StaticCombinator {
+    /// Compiles a `StaticCombinator` to CAM code. The code for the combinator itself starts at
+    /// the first instruction; the code for closures follows it.
+    pub fn to_instructions(&self) -> Vec<Instruction> {
+        let mut code = Vec::new();
+        let mut bodies = Vec::new();
+        self.compile_to_instructions(&mut code, &mut bodies);
+        code.push(Instruction::Return);
+        while let Some((cur, body)) = bodies.pop() {
+            code[cur] = match code[cur] {
+                Instruction::Rec(_) => Instruction::Rec(code.len()),
+                _ => Instruction::Cur(code.len()),
+            };
+            body.compile_to_instructions(&mut code, &mut bodies);
+            code.push(Instruction::Return);
+        }
+        code
+    }
+
+    /// Compiles a combinator to CAM code, recording the bodies of closures (and the addresses of
+    /// the `Cur` or `Rec` instructions referencing them) to be compiled later.
+    fn compile_to_instructions<'a>(
+        &'a self,
+        code: &mut Vec<Instruction>,
+        bodies: &mut Vec<(usize, &'a StaticCombinator)>,
+    ) {
+        match *self {
+            StaticCombinator::Access(n) => code.push(Instruction::Access(n)),
+            StaticCombinator::App => code.push(Instruction::App),
+            StaticCombinator::Bind(ref x) => {
+                code.push(Instruction::Push);
+                x.compile_to_instructions(code, bodies);
+                code.push(Instruction::Cons);
+            }
+            StaticCombinator::Branch(ref x, ref y) => {
+                let branch = code.len();
+                code.push(Instruction::Branch(0));
+                x.compile_to_instructions(code, bodies);
+                let jump = code.len();
+                code.push(Instruction::Jump(0));
+                code[branch] = Instruction::Branch(code.len());
+                y.compile_to_instructions(code, bodies);
+                code[jump] = Instruction::Jump(code.len());
+            }
+            StaticCombinator::Case(ref x, ref y) => {
+                let case = code.len();
+                code.push(Instruction::Case(0));
+                x.compile_to_instructions(code, bodies);
+                let jump = code.len();
+                code.push(Instruction::Jump(0));
+                code[case] = Instruction::Case(code.len());
+                y.compile_to_instructions(code, bodies);
+                code[jump] = Instruction::Jump(code.len());
+            }
+            StaticCombinator::Com(ref l, ref r) => {
+                r.compile_to_instructions(code, bodies);
+                l.compile_to_instructions(code, bodies);
+            }
+            StaticCombinator::Fst => code.push(Instruction::Car),
+            StaticCombinator::Id => {}
+            StaticCombinator::Inl => code.push(Instruction::Inl),
+            StaticCombinator::Inr => code.push(Instruction::Inr),
+            StaticCombinator::Lam(ref b) => {
+                bodies.push((code.len(), b));
+                code.push(Instruction::Cur(0));
+            }
+            StaticCombinator::Pair(ref l, ref r) => {
+                code.push(Instruction::Push);
+                l.compile_to_instructions(code, bodies);
+                code.push(Instruction::Swap);
+                r.compile_to_instructions(code, bodies);
+                code.push(Instruction::Cons);
+            }
+            StaticCombinator::QuoteBool(b) => code.push(Instruction::Quote(Value::Bool(b))),
+            StaticCombinator::QuoteDef(ref m, ref n) => {
+                unreachable!("definitions are bound by lets before running: {}.{}", m, n)
+            }
+            StaticCombinator::QuoteName(ref n) => {
+                code.push(Instruction::Quote(Value::Builtin(n.clone(), Vec::new())))
+            }
+            StaticCombinator::QuoteNum(n) => code.push(Instruction::Quote(Value::Num(n))),
+            StaticCombinator::QuoteUnit => code.push(Instruction::Quote(Value::Unit)),
+            StaticCombinator::Rec(ref b) => {
+                bodies.push((code.len(), b));
+                code.push(Instruction::Rec(0));
+            }
+            StaticCombinator::Seq(ref cs) => {
+                for c in cs.iter().rev() {
+                    c.compile_to_instructions(code, bodies);
+                }
+            }
+            StaticCombinator::Snd => code.push(Instruction::Cdr),
+            StaticCombinator::Then(ref x, ref y) => {
+                code.push(Instruction::Push);
+                x.compile_to_instructions(code, bodies);
+                code.push(Instruction::Pop);
+                y.compile_to_instructions(code, bodies);
+            }
+        }
+    }
+}
+
+impl Expr {
+    /// Compiles the expression to CAM code.
+    pub fn to_instructions(&self) -> Result<Vec<Instruction>, Error> {
+        self.to_combinators()
+            .map(|combinators| combinators.to_instructions())
+    }
+}
@@ -1,19 +1,41 @@
 //! Compiles the nameless lambda calculus to the Categorical Abstract Machine.
 
 pub(crate) mod eval;
+pub(crate) mod machine;
+pub(crate) mod opt;
 
 use crate::{
     ast::Expr,
     nameless::{remove_names, NamelessExpr},
+    CompileOptions, Error,
 };
 
 /// A combinator that is compiled to.
 #[derive(Clone, Debug, Display, PartialEq)]
 pub enum StaticCombinator {
+    /// The environment access combinator. `Access(n) = Snd∘Fst∘...∘Fst`, with `n` `Fst`s, so
+    /// `Access(n)` gets the variable with De Bruijn index `n`.
+    #[display(fmt = "Access({})", _0)]
+    Access(usize),
+
     /// The `App` combinator. `App(Λ(x)y, z) = x(y, z)`
     #[display(fmt = "App")]
     App,
 
+    /// The `Bind` combinator, which extends an environment. `Bind(x)z = (z, xz)`, i.e. `Bind(x)` is
+    /// `<Id, x>`.
+    #[display(fmt = "Bind({})", _0)]
+    Bind(Box<StaticCombinator>),
+
+    /// The `Branch` combinator. `Branch(x, y)(z, true) = xz` and `Branch(x, y)(z, false) = yz`
+    #[display(fmt = "Branch({}, {})", _0, _1)]
+    Branch(Box<StaticCombinator>, Box<StaticCombinator>),
+
+    /// The `Case` combinator. `Case(x, y)(z, inl v) = x(z, v)` and
+    /// `Case(x, y)(z, inr v) = y(z, v)`
+    #[display(fmt = "Case({}, {})", _0, _1)]
+    Case(Box<StaticCombinator>, Box<StaticCombinator>),
+
     /// The composition combinator. `(x∘y)z = x(yz)`
     #[display(fmt = "{} ∘ {}", _0, _1)]
     Com(Box<StaticCombinator>, Box<StaticCombinator>),
@@ -22,6 +44,18 @@ pub enum StaticCombinator {
     #[display(fmt = "Fst")]
     Fst,
 
+    /// The identity combinator. `Id x = x`
+    #[display(fmt = "Id")]
+    Id,
+
+    /// The `Inl` combinator, which tags a value as the left alternative of a sum. `Inl x = inl x`
+    #[display(fmt = "Inl")]
+    Inl,
+
+    /// The `Inr` combinator, which tags a value as the right alternative of a sum. `Inr x = inr x`
+    #[display(fmt = "Inr")]
+    Inr,
+
     /// The `Λ` combinator. `App(Λ(x)y, z) = x(y, z)`
     #[display(fmt = "Λ({})", _0)]
     Lam(Box<StaticCombinator>),
@@ -30,41 +64,130 @@ pub enum StaticCombinator {
     #[display(fmt = "<{}, {}>", _0, _1)]
     Pair(Box<StaticCombinator>, Box<StaticCombinator>),
 
+    /// The `'` combinator, specialized to a boolean. `'xy = x`
+    #[display(fmt = "'{}", _0)]
+    QuoteBool(bool),
+
+    /// The `'` combinator, specialized to the value of a top-level definition, given by the prefix
+    /// of its module and its name. `'xy = x`
+    #[display(fmt = "'{}.{}", _0, _1)]
+    QuoteDef(String, String),
+
     /// The `'` combinator, specialized to a name. `'xy = x`
     #[display(fmt = "'{}", _0)]
     QuoteName(String),
 
     /// The `'` combinator, specialized to a number. `'xy = x`
     #[display(fmt = "'{}", _0)]
-    QuoteNum(u32),
+    QuoteNum(i64),
+
+    /// The `'` combinator, specialized to the unit value. `'xy = x`
+    #[display(fmt = "'()")]
+    QuoteUnit,
+
+    /// The `Rec` combinator, which extends an environment with a recursive closure. `Rec(x)z = w`,
+    /// where `w = (z, Λ(x)w)`.
+    #[display(fmt = "Rec({})", _0)]
+    Rec(Box<StaticCombinator>),
+
+    /// A chain of compositions. `Seq[x, y, z] = x∘y∘z`, so the last combinator is applied first.
+    /// Chains built with `compose` have at least three combinators.
+    #[display(fmt = "{}", "itertools::join(_0, \" ∘ \")")]
+    Seq(Vec<StaticCombinator>),
 
     /// The `Snd` combinator. `Snd(x, y) = y`
     #[display(fmt = "Snd")]
     Snd,
+
+    /// The sequencing combinator. `Then(x, y)z = yz`, but `xz` is evaluated first for its effects.
+    #[display(fmt = "Then({}, {})", _0, _1)]
+    Then(Box<StaticCombinator>, Box<StaticCombinator>),
 }
 
 impl StaticCombinator {
+    /// Composes the given combinators, so that the last is applied first. Nested chains are
+    /// flattened and `Id`s are dropped; a chain of two combinators is built with `Com`, and a longer
+    /// one with `Seq`.
+    pub fn compose(cs: Vec<StaticCombinator>) -> StaticCombinator {
+        let mut chain = StaticCombinator::flatten(cs);
+        chain.retain(|c| *c != StaticCombinator::Id);
+        StaticCombinator::chain(chain)
+    }
+
+    /// Flattens nested compositions in a chain of compositions.
+    pub(crate) fn flatten(cs: Vec<StaticCombinator>) -> Vec<StaticCombinator> {
+        let mut chain = Vec::with_capacity(cs.len());
+        let mut stack = cs;
+        stack.reverse();
+        while let Some(c) = stack.pop() {
+            match c {
+                StaticCombinator::Com(l, r) => {
+                    stack.push(*r);
+                    stack.push(*l);
+                }
+                StaticCombinator::Seq(cs) => stack.extend(cs.into_iter().rev()),
+                c => chain.push(c),
+            }
+        }
+        chain
+    }
+
+    /// Builds a chain of compositions from the given combinators as they are, without flattening.
+    pub(crate) fn chain(mut cs: Vec<StaticCombinator>) -> StaticCombinator {
+        match cs.len() {
+            0 => StaticCombinator::Id,
+            1 => cs.pop().unwrap(),
+            2 => {
+                let r = cs.pop().unwrap();
+                let l = cs.pop().unwrap();
+                StaticCombinator::Com(Box::new(l), Box::new(r))
+            }
+            _ => StaticCombinator::Seq(cs),
+        }
+    }
+
     /// Compiles a `StaticCombinator` to a sequence of Forth definitions. The names generated are
     /// prefixed with the given string.
     pub fn to_forth(&self, prefix: &str) -> Vec<Vec<String>> {
         let mut decls = Vec::new();
-        let mut counter = 0;
-        let mut main = self.compile_to_forth(&mut decls, &mut || {
+        self.to_forth_word("main", prefix, &mut 0, &mut decls);
+        decls
+    }
+
+    /// Compiles a `StaticCombinator` to the Forth definition of the top-level definition `name`,
+    /// which applies the combinator to an empty environment. `main` becomes a word named
+    /// `fstlc-{prefix}-main`, which runs the program. Any other definition is evaluated once, when
+    /// the program is loaded, by a word named `fstlc-init-{prefix}-{name}`, and its value is stored
+    /// in `fstlc-{prefix}-{name}`. The declarations are added to `decls`, after the lambdas they
+    /// use. `counter` is used to number the lambdas, so it should be shared between words with the
+    /// same prefix.
+    pub(crate) fn to_forth_word(
+        &self,
+        name: &str,
+        prefix: &str,
+        counter: &mut usize,
+        decls: &mut Vec<Vec<String>>,
+    ) {
+        let mut hoisted = Vec::new();
+        let mut main = self.compile_to_forth(&mut hoisted, &mut || {
             let name = format!("fstlc-lambda-{}-{}", prefix, counter);
-            counter += 1;
+            *counter += 1;
             name
         });
         main.insert(0, "0".to_string());
-        decls.push((format!("fstlc-{}-main", prefix), main));
-        decls
-            .into_iter()
-            .map(|(name, mut words)| {
-                words.insert(0, name);
-                words.insert(0, ":".to_string());
-                words.push(";".to_string());
-                words
-            })
-            .collect()
+        let word = format!("fstlc-{}-{}", prefix, name);
+        let init = format!("fstlc-init-{}-{}", prefix, name);
+        let is_main = name == "main";
+        hoisted.push((if is_main { word.clone() } else { init.clone() }, main));
+        decls.extend(hoisted.into_iter().map(|(name, mut words)| {
+            words.insert(0, name);
+            words.insert(0, ":".to_string());
+            words.push(";".to_string());
+            words
+        }));
+        if !is_main {
+            decls.push(vec![init, "FSTLC-DEF".to_string(), word]);
+        }
     }
 
     /// Compiles a combinator to a chunk of Forth code, possibly adding global declarations while
@@ -75,18 +198,45 @@ impl StaticCombinator {
         fresh_name: &mut impl FnMut() -> String,
     ) -> Vec<String> {
         match *self {
+            StaticCombinator::Access(1) => vec!["FSTLC-FST".to_string(), "FSTLC-SND".to_string()],
+            StaticCombinator::Access(n) => vec![n.to_string(), "FSTLC-ACCESS".to_string()],
             StaticCombinator::App => vec!["FSTLC-APP".to_string()],
+            StaticCombinator::Bind(ref x) => {
+                let mut v = vec!["DUP".to_string()];
+                v.extend(x.compile_to_forth(hoisted, fresh_name));
+                v.push("FSTLC-MAKE-PAIR".to_string());
+                v
+            }
+            StaticCombinator::Branch(ref x, ref y) => {
+                let mut v = vec!["FSTLC-BRANCH".to_string(), "IF".to_string()];
+                v.extend(x.compile_to_forth(hoisted, fresh_name));
+                v.push("ELSE".to_string());
+                v.extend(y.compile_to_forth(hoisted, fresh_name));
+                v.push("THEN".to_string());
+                v
+            }
+            StaticCombinator::Case(ref x, ref y) => {
+                let mut v = vec!["FSTLC-CASE".to_string(), "IF".to_string()];
+                v.extend(x.compile_to_forth(hoisted, fresh_name));
+                v.push("ELSE".to_string());
+                v.extend(y.compile_to_forth(hoisted, fresh_name));
+                v.push("THEN".to_string());
+                v
+            }
             StaticCombinator::Com(ref l, ref r) => {
                 let mut v = r.compile_to_forth(hoisted, fresh_name);
                 v.extend(l.compile_to_forth(hoisted, fresh_name));
                 v
             }
             StaticCombinator::Fst => vec!["FSTLC-FST".to_string()],
+            StaticCombinator::Id => Vec::new(),
+            StaticCombinator::Inl => vec!["FSTLC-INL".to_string()],
+            StaticCombinator::Inr => vec!["FSTLC-INR".to_string()],
             StaticCombinator::Lam(ref b) => {
                 let inner = b.compile_to_forth(hoisted, fresh_name);
                 let name = fresh_name();
                 hoisted.push((name.clone(), inner));
-                vec!["'".to_string(), name]
+                vec!["'".to_string(), name, "FSTLC-MAKE-CLOSURE".to_string()]
             }
             StaticCombinator::Pair(ref l, ref r) => {
                 let mut v = vec!["DUP".to_string()];
@@ -96,41 +246,173 @@ impl StaticCombinator {
                 v.push("FSTLC-MAKE-PAIR".to_string());
                 v
             }
-            StaticCombinator::QuoteName(ref n) => {
-                vec!["DROP".to_string(), "'".to_string(), format!("{}", n)]
+            StaticCombinator::QuoteBool(b) => {
+                vec!["DROP".to_string(), if b { "-1" } else { "0" }.to_string()]
             }
+            StaticCombinator::QuoteDef(ref m, ref n) if n == "main" => {
+                vec!["DROP".to_string(), format!("fstlc-{}-{}", m, n)]
+            }
+            StaticCombinator::QuoteDef(ref m, ref n) => vec![
+                "DROP".to_string(),
+                format!("fstlc-{}-{}", m, n),
+                "@".to_string(),
+            ],
+            StaticCombinator::QuoteName(ref n) => vec![
+                "DROP".to_string(),
+                format!("FSTLC-BUILTIN-{}", n.to_uppercase()),
+            ],
             StaticCombinator::QuoteNum(n) => vec!["DROP".to_string(), format!("{}", n)],
+            StaticCombinator::QuoteUnit => vec!["DROP".to_string(), "0".to_string()],
+            StaticCombinator::Rec(ref b) => {
+                let inner = b.compile_to_forth(hoisted, fresh_name);
+                let name = fresh_name();
+                hoisted.push((name.clone(), inner));
+                vec!["'".to_string(), name, "FSTLC-REC".to_string()]
+            }
+            StaticCombinator::Seq(ref cs) => cs
+                .iter()
+                .rev()
+                .flat_map(|c| c.compile_to_forth(hoisted, fresh_name))
+                .collect(),
             StaticCombinator::Snd => vec!["FSTLC-SND".to_string()],
+            StaticCombinator::Then(ref x, ref y) => {
+                // The unit value x leaves on the stack is dropped instead of being paired with z.
+                let mut v = vec!["DUP".to_string()];
+                v.extend(x.compile_to_forth(hoisted, fresh_name));
+                v.push("DROP".to_string());
+                v.extend(y.compile_to_forth(hoisted, fresh_name));
+                v
+            }
         }
     }
 }
 
+/// Performs a comparison builtin. Returns `None` if the builtin is unknown.
+pub(crate) fn cmpop(name: &str, l: i64, r: i64) -> Option<bool> {
+    match name {
+        "=" => Some(l == r),
+        "<>" => Some(l != r),
+        "<" => Some(l < r),
+        "<=" => Some(l <= r),
+        ">" => Some(l > r),
+        ">=" => Some(l >= r),
+        _ => None,
+    }
+}
+
+/// Performs a binary arithmetic builtin. Returns `None` if the builtin is unknown or the result is
+/// undefined (i.e. on division by zero).
+///
+/// Integers are signed and 64 bits wide. All operations wrap on overflow. Division truncates
+/// towards zero, and `mod` takes the sign of the dividend. The reference evaluators are always
+/// 64-bit, so Forth code compiled for a narrower cell may wrap where they do not.
+pub(crate) fn binop(name: &str, l: i64, r: i64) -> Option<i64> {
+    match name {
+        "+" => Some(l.wrapping_add(r)),
+        "-" => Some(l.wrapping_sub(r)),
+        "*" => Some(l.wrapping_mul(r)),
+        "/" if r != 0 => Some(l.wrapping_div(r)),
+        "mod" if r != 0 => Some(l.wrapping_rem(r)),
+        _ => None,
+    }
+}
+
 impl Expr {
     /// Compiles a `Expr` into a `StaticCombinator`.
-    pub fn to_combinators(&self) -> Result<StaticCombinator, String> {
-        remove_names(&mut Vec::new(), self).map(|nameless| nameless.to_combinator())
+    pub fn to_combinators(&self) -> Result<StaticCombinator, Error> {
+        self.to_combinators_with(&CompileOptions::default())
+    }
+
+    /// Compiles a `Expr` into a `StaticCombinator`, with the given options.
+    pub fn to_combinators_with(&self, options: &CompileOptions) -> Result<StaticCombinator, Error> {
+        remove_names(&[], &mut Vec::new(), self)
+            .map(|nameless| nameless.to_combinator_with(options))
     }
 }
 
 impl NamelessExpr {
+    /// Compiles a `NamelessExpr` into a `StaticCombinator`, converting closures and optimizing as
+    /// the given options say.
+    pub fn to_combinator_with(&self, options: &CompileOptions) -> StaticCombinator {
+        let c = if options.flat_closures {
+            self.closure_convert().to_combinator()
+        } else {
+            self.to_combinator()
+        };
+        c.optimize(&options.rules)
+    }
+
     /// Compiles a `NamelessExpr` into a `StaticCombinator`.
     pub fn to_combinator(&self) -> StaticCombinator {
         match *self {
-            NamelessExpr::App(ref l, ref r) => StaticCombinator::Com(
-                Box::new(StaticCombinator::App),
-                Box::new(StaticCombinator::Pair(
-                    Box::new(l.to_combinator()),
-                    Box::new(r.to_combinator()),
-                )),
-            ),
+            NamelessExpr::App(ref l, ref r) => StaticCombinator::compose(vec![
+                StaticCombinator::App,
+                StaticCombinator::Pair(Box::new(l.to_combinator()), Box::new(r.to_combinator())),
+            ]),
+            NamelessExpr::Bool(b) => StaticCombinator::QuoteBool(b),
+            NamelessExpr::Case(ref e, ref a, ref b) => StaticCombinator::compose(vec![
+                StaticCombinator::Case(Box::new(a.to_combinator()), Box::new(b.to_combinator())),
+                StaticCombinator::Bind(Box::new(e.to_combinator())),
+            ]),
+            NamelessExpr::Closure(ref cs, ref b) => StaticCombinator::compose(vec![
+                StaticCombinator::Lam(Box::new(b.to_combinator())),
+                flat_env(cs),
+            ]),
+            NamelessExpr::Def(ref m, ref n) => StaticCombinator::QuoteDef(m.clone(), n.clone()),
+            NamelessExpr::Fst(ref e) => {
+                StaticCombinator::compose(vec![StaticCombinator::Fst, e.to_combinator()])
+            }
+            NamelessExpr::If(ref c, ref a, ref b) => StaticCombinator::compose(vec![
+                StaticCombinator::Branch(Box::new(a.to_combinator()), Box::new(b.to_combinator())),
+                StaticCombinator::Bind(Box::new(c.to_combinator())),
+            ]),
+            NamelessExpr::Inl(ref e) => {
+                StaticCombinator::compose(vec![StaticCombinator::Inl, e.to_combinator()])
+            }
+            NamelessExpr::Inr(ref e) => {
+                StaticCombinator::compose(vec![StaticCombinator::Inr, e.to_combinator()])
+            }
             NamelessExpr::Lam(ref b) => StaticCombinator::Lam(Box::new(b.to_combinator())),
+            NamelessExpr::Let(ref e1, ref e2) => StaticCombinator::compose(vec![
+                e2.to_combinator(),
+                StaticCombinator::Bind(Box::new(e1.to_combinator())),
+            ]),
+            NamelessExpr::LetRec(ref b, ref e) => StaticCombinator::compose(vec![
+                e.to_combinator(),
+                StaticCombinator::Rec(Box::new(b.to_combinator())),
+            ]),
             NamelessExpr::Lit(n) => StaticCombinator::QuoteNum(n),
             NamelessExpr::Nam(ref n) => StaticCombinator::QuoteName(n.clone()),
+            NamelessExpr::RecClosure(ref cs, ref b) => StaticCombinator::compose(vec![
+                StaticCombinator::Snd,
+                StaticCombinator::Rec(Box::new(b.to_combinator())),
+                flat_env(cs),
+            ]),
+            NamelessExpr::Seq(ref e1, ref e2) => {
+                StaticCombinator::Then(Box::new(e1.to_combinator()), Box::new(e2.to_combinator()))
+            }
+            NamelessExpr::Snd(ref e) => {
+                StaticCombinator::compose(vec![StaticCombinator::Snd, e.to_combinator()])
+            }
+            NamelessExpr::Tuple(ref l, ref r) => {
+                StaticCombinator::Pair(Box::new(l.to_combinator()), Box::new(r.to_combinator()))
+            }
+            NamelessExpr::Unit => StaticCombinator::QuoteUnit,
             NamelessExpr::Var(0) => StaticCombinator::Snd,
-            NamelessExpr::Var(n) => StaticCombinator::Com(
-                Box::new(NamelessExpr::Var(n - 1).to_combinator()),
-                Box::new(StaticCombinator::Fst),
-            ),
+            NamelessExpr::Var(n) => StaticCombinator::Access(n),
         }
     }
 }
+
+/// Builds the environment of a flat closure capturing the given variables, which is
+/// `<…<<'(), $n>, …>, $0>` for the variables `[$0, …, $n]`.
+fn flat_env(cs: &[usize]) -> StaticCombinator {
+    cs.iter()
+        .rev()
+        .fold(StaticCombinator::QuoteUnit, |env, &c| {
+            StaticCombinator::Pair(
+                Box::new(env),
+                Box::new(NamelessExpr::Var(c).to_combinator()),
+            )
+        })
+}
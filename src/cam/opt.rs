@@ -0,0 +1,215 @@
+//! A peephole optimizer over combinators, which rewrites them using the equations of the CAM.
+//!
+//! Rules that would discard a combinator only fire when that combinator is pure, i.e. when
+//! applying it cannot print, diverge, or fail. Applications are never considered pure. References
+//! to top-level definitions only read values computed when the program was loaded, so they are
+//! pure, except for references to `main`, which run it.
+
+use crate::cam::StaticCombinator;
+use std::{
+    fmt::{Display, Formatter, Result as FmtResult},
+    iter,
+    str::FromStr,
+};
+
+/// A rewrite rule.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Rule {
+    /// Fuses projections out of an environment. `Snd∘Fst → Access(1)` and
+    /// `Access(n)∘Fst → Access(n + 1)`
+    Access,
+
+    /// Flattens nested compositions into a single chain. `(x∘y)∘z → Seq[x, y, z]`
+    Assoc,
+
+    /// Applies a closure that is built and immediately applied. `App∘<Λ(x), y> → x∘Bind(y)`
+    Beta,
+
+    /// Projects the first component of a pair. `Fst∘<x, y> → x`, if `y` is pure, and
+    /// `Fst∘Bind(x) → Id`, if `x` is pure.
+    FstPair,
+
+    /// Drops identities from compositions. `Id∘x → x` and `x∘Id → x`
+    Id,
+
+    /// Drops the argument of a quotation. `'c∘x → 'c`, if `x` is pure.
+    Quote,
+
+    /// Projects the second component of a pair. `Snd∘<x, y> → y`, if `x` is pure, and
+    /// `Snd∘Bind(x) → x`.
+    SndPair,
+}
+
+impl Rule {
+    /// Every rule.
+    pub const ALL: &'static [Rule] = &[
+        Rule::Access,
+        Rule::Assoc,
+        Rule::Beta,
+        Rule::FstPair,
+        Rule::Id,
+        Rule::Quote,
+        Rule::SndPair,
+    ];
+}
+
+impl Display for Rule {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        let name = match self {
+            Rule::Access => "access",
+            Rule::Assoc => "assoc",
+            Rule::Beta => "beta",
+            Rule::FstPair => "fst-pair",
+            Rule::Id => "id",
+            Rule::Quote => "quote",
+            Rule::SndPair => "snd-pair",
+        };
+        write!(fmt, "{}", name)
+    }
+}
+
+impl FromStr for Rule {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Rule, String> {
+        Rule::ALL
+            .iter()
+            .find(|rule| rule.to_string() == s)
+            .cloned()
+            .ok_or_else(|| format!("Unknown optimization rule: {}", s))
+    }
+}
+
+impl StaticCombinator {
+    /// Rewrites the combinator with the given rules until none of them apply.
+    pub fn optimize(&self, rules: &[Rule]) -> StaticCombinator {
+        let mut c = self.clone();
+        loop {
+            let next = c.clone().rewrite(rules);
+            if next == c {
+                return c;
+            }
+            c = next;
+        }
+    }
+
+    /// Returns whether applying the combinator to a value always returns a value, with no effects.
+    pub fn is_pure(&self) -> bool {
+        match *self {
+            StaticCombinator::App => false,
+            StaticCombinator::QuoteDef(_, ref n) => n != "main",
+            StaticCombinator::Access(_)
+            | StaticCombinator::Fst
+            | StaticCombinator::Id
+            | StaticCombinator::Inl
+            | StaticCombinator::Inr
+            | StaticCombinator::Lam(_)
+            | StaticCombinator::QuoteBool(_)
+            | StaticCombinator::QuoteName(_)
+            | StaticCombinator::QuoteNum(_)
+            | StaticCombinator::QuoteUnit
+            | StaticCombinator::Rec(_)
+            | StaticCombinator::Snd => true,
+            StaticCombinator::Bind(ref x) => x.is_pure(),
+            StaticCombinator::Branch(ref x, ref y)
+            | StaticCombinator::Case(ref x, ref y)
+            | StaticCombinator::Com(ref x, ref y)
+            | StaticCombinator::Pair(ref x, ref y)
+            | StaticCombinator::Then(ref x, ref y) => x.is_pure() && y.is_pure(),
+            StaticCombinator::Seq(ref cs) => cs.iter().all(StaticCombinator::is_pure),
+        }
+    }
+
+    /// Returns whether the combinator is a quotation.
+    fn is_quote(&self) -> bool {
+        matches!(
+            *self,
+            StaticCombinator::QuoteBool(_)
+                | StaticCombinator::QuoteDef(..)
+                | StaticCombinator::QuoteName(_)
+                | StaticCombinator::QuoteNum(_)
+                | StaticCombinator::QuoteUnit
+        )
+    }
+
+    /// Rewrites the children of the combinator, then the combinator itself, once.
+    fn rewrite(self, rules: &[Rule]) -> StaticCombinator {
+        let r = |c: Box<StaticCombinator>| Box::new(c.rewrite(rules));
+        match self {
+            StaticCombinator::Bind(x) => StaticCombinator::Bind(r(x)),
+            StaticCombinator::Branch(x, y) => StaticCombinator::Branch(r(x), r(y)),
+            StaticCombinator::Case(x, y) => StaticCombinator::Case(r(x), r(y)),
+            StaticCombinator::Com(x, y) => rewrite_chain(vec![*r(x), *r(y)], rules),
+            StaticCombinator::Lam(x) => StaticCombinator::Lam(r(x)),
+            StaticCombinator::Pair(x, y) => StaticCombinator::Pair(r(x), r(y)),
+            StaticCombinator::Rec(x) => StaticCombinator::Rec(r(x)),
+            StaticCombinator::Seq(cs) => {
+                rewrite_chain(cs.into_iter().map(|c| c.rewrite(rules)).collect(), rules)
+            }
+            StaticCombinator::Then(x, y) => StaticCombinator::Then(r(x), r(y)),
+            c => c,
+        }
+    }
+}
+
+/// Rewrites a chain of compositions, by rewriting adjacent pairs of combinators in it.
+fn rewrite_chain(cs: Vec<StaticCombinator>, rules: &[Rule]) -> StaticCombinator {
+    let mut cs = if rules.contains(&Rule::Assoc) {
+        StaticCombinator::flatten(cs)
+    } else {
+        cs
+    };
+
+    let mut i = 0;
+    while i + 1 < cs.len() {
+        match rewrite_pair(&cs[i], &cs[i + 1], rules) {
+            Some(c) => {
+                cs.splice(i..i + 2, iter::once(c));
+                // The new combinator may form a pair with the one before it.
+                i = i.saturating_sub(1);
+            }
+            None => i += 1,
+        }
+    }
+    StaticCombinator::chain(cs)
+}
+
+/// Rewrites the composition `l∘r`, if any rule matches it.
+fn rewrite_pair(
+    l: &StaticCombinator,
+    r: &StaticCombinator,
+    rules: &[Rule],
+) -> Option<StaticCombinator> {
+    let rule = match (l, r) {
+        (StaticCombinator::Snd, StaticCombinator::Fst) => {
+            (Rule::Access, StaticCombinator::Access(1))
+        }
+        (StaticCombinator::Access(n), StaticCombinator::Fst) => {
+            (Rule::Access, StaticCombinator::Access(n + 1))
+        }
+        (StaticCombinator::App, StaticCombinator::Pair(x, y)) => match **x {
+            StaticCombinator::Lam(ref x) => (
+                Rule::Beta,
+                StaticCombinator::Com(x.clone(), Box::new(StaticCombinator::Bind(y.clone()))),
+            ),
+            _ => return None,
+        },
+        (StaticCombinator::Fst, StaticCombinator::Pair(x, y)) if y.is_pure() => {
+            (Rule::FstPair, (**x).clone())
+        }
+        (StaticCombinator::Fst, StaticCombinator::Bind(x)) if x.is_pure() => {
+            (Rule::FstPair, StaticCombinator::Id)
+        }
+        (StaticCombinator::Id, x) | (x, StaticCombinator::Id) => (Rule::Id, x.clone()),
+        (StaticCombinator::Snd, StaticCombinator::Pair(x, y)) if x.is_pure() => {
+            (Rule::SndPair, (**y).clone())
+        }
+        (StaticCombinator::Snd, StaticCombinator::Bind(x)) => (Rule::SndPair, (**x).clone()),
+        (l, r) if l.is_quote() && r.is_pure() => (Rule::Quote, l.clone()),
+        _ => return None,
+    };
+    if rules.contains(&rule.0) {
+        Some(rule.1)
+    } else {
+        None
+    }
+}
@@ -0,0 +1,121 @@
+//! Flat closure conversion.
+//!
+//! By default, a closure captures the whole environment it is created in, which is a linked list
+//! of every variable in scope. This pass instead gives each closure an environment holding only
+//! the variables that are free in its body, and renumbers the variables in the body to match.
+
+use crate::nameless::NamelessExpr;
+
+impl NamelessExpr {
+    /// Converts every function abstraction in the expression to a flat closure. Recursive
+    /// functions become `let` bindings of recursive flat closures.
+    pub fn closure_convert(&self) -> NamelessExpr {
+        let c = |e: &NamelessExpr| Box::new(e.closure_convert());
+        match *self {
+            NamelessExpr::App(ref l, ref r) => NamelessExpr::App(c(l), c(r)),
+            NamelessExpr::Case(ref e, ref a, ref b) => NamelessExpr::Case(c(e), c(a), c(b)),
+            NamelessExpr::Fst(ref e) => NamelessExpr::Fst(c(e)),
+            NamelessExpr::If(ref e, ref a, ref b) => NamelessExpr::If(c(e), c(a), c(b)),
+            NamelessExpr::Inl(ref e) => NamelessExpr::Inl(c(e)),
+            NamelessExpr::Inr(ref e) => NamelessExpr::Inr(c(e)),
+            NamelessExpr::Lam(ref b) => {
+                let (captured, b) = flatten(&b.closure_convert(), 1);
+                NamelessExpr::Closure(captured, Box::new(b))
+            }
+            NamelessExpr::Let(ref e1, ref e2) => NamelessExpr::Let(c(e1), c(e2)),
+            NamelessExpr::LetRec(ref b, ref e) => {
+                let (captured, b) = flatten(&b.closure_convert(), 2);
+                NamelessExpr::Let(
+                    Box::new(NamelessExpr::RecClosure(captured, Box::new(b))),
+                    c(e),
+                )
+            }
+            NamelessExpr::Seq(ref e1, ref e2) => NamelessExpr::Seq(c(e1), c(e2)),
+            NamelessExpr::Snd(ref e) => NamelessExpr::Snd(c(e)),
+            NamelessExpr::Tuple(ref l, ref r) => NamelessExpr::Tuple(c(l), c(r)),
+            ref e => e.clone(),
+        }
+    }
+
+    /// Returns the variables of the expression that refer past `depth` binders, relative to those
+    /// binders, in ascending order and without duplicates.
+    fn free_vars(&self, depth: usize) -> Vec<usize> {
+        let mut vars = Vec::new();
+        self.map_vars(depth, &mut |n| {
+            vars.push(n);
+            n
+        });
+        vars.sort_unstable();
+        vars.dedup();
+        vars
+    }
+
+    /// Replaces each variable that refers past `depth` binders, by applying `f` to its index
+    /// relative to those binders. Lambdas must already have been converted to closures, whose
+    /// bodies are closed and so are left as is.
+    fn map_vars(&self, depth: usize, f: &mut impl FnMut(usize) -> usize) -> NamelessExpr {
+        match *self {
+            NamelessExpr::App(ref l, ref r) => NamelessExpr::App(
+                Box::new(l.map_vars(depth, f)),
+                Box::new(r.map_vars(depth, f)),
+            ),
+            NamelessExpr::Case(ref e, ref a, ref b) => NamelessExpr::Case(
+                Box::new(e.map_vars(depth, f)),
+                Box::new(a.map_vars(depth + 1, f)),
+                Box::new(b.map_vars(depth + 1, f)),
+            ),
+            NamelessExpr::Closure(ref cs, ref b) => NamelessExpr::Closure(
+                cs.iter().map(|&n| map_var(n, depth, f)).collect(),
+                b.clone(),
+            ),
+            NamelessExpr::Fst(ref e) => NamelessExpr::Fst(Box::new(e.map_vars(depth, f))),
+            NamelessExpr::If(ref c, ref a, ref b) => NamelessExpr::If(
+                Box::new(c.map_vars(depth, f)),
+                Box::new(a.map_vars(depth, f)),
+                Box::new(b.map_vars(depth, f)),
+            ),
+            NamelessExpr::Inl(ref e) => NamelessExpr::Inl(Box::new(e.map_vars(depth, f))),
+            NamelessExpr::Inr(ref e) => NamelessExpr::Inr(Box::new(e.map_vars(depth, f))),
+            NamelessExpr::Lam(ref b) => NamelessExpr::Lam(Box::new(b.map_vars(depth + 1, f))),
+            NamelessExpr::Let(ref e1, ref e2) => NamelessExpr::Let(
+                Box::new(e1.map_vars(depth, f)),
+                Box::new(e2.map_vars(depth + 1, f)),
+            ),
+            NamelessExpr::LetRec(ref b, ref e) => NamelessExpr::LetRec(
+                Box::new(b.map_vars(depth + 2, f)),
+                Box::new(e.map_vars(depth + 1, f)),
+            ),
+            NamelessExpr::RecClosure(ref cs, ref b) => NamelessExpr::RecClosure(
+                cs.iter().map(|&n| map_var(n, depth, f)).collect(),
+                b.clone(),
+            ),
+            NamelessExpr::Seq(ref e1, ref e2) => NamelessExpr::Seq(
+                Box::new(e1.map_vars(depth, f)),
+                Box::new(e2.map_vars(depth, f)),
+            ),
+            NamelessExpr::Snd(ref e) => NamelessExpr::Snd(Box::new(e.map_vars(depth, f))),
+            NamelessExpr::Tuple(ref l, ref r) => NamelessExpr::Tuple(
+                Box::new(l.map_vars(depth, f)),
+                Box::new(r.map_vars(depth, f)),
+            ),
+            NamelessExpr::Var(n) => NamelessExpr::Var(map_var(n, depth, f)),
+            ref e => e.clone(),
+        }
+    }
+}
+
+/// Finds the variables a converted function body captures from past its `binders` innermost
+/// variables, and renumbers the body so that the `j`th captured variable is `$(binders + j)`.
+fn flatten(body: &NamelessExpr, binders: usize) -> (Vec<usize>, NamelessExpr) {
+    let captured = body.free_vars(binders);
+    let body = body.map_vars(binders, &mut |n| captured.binary_search(&n).unwrap());
+    (captured, body)
+}
+
+fn map_var(n: usize, depth: usize, f: &mut impl FnMut(usize) -> usize) -> usize {
+    if n >= depth {
+        depth + f(n - depth)
+    } else {
+        n
+    }
+}
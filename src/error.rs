@@ -0,0 +1,368 @@
+use crate::{Span, Type};
+use itertools::Itertools;
+use lalrpop_util::ParseError;
+use std::{
+    fmt::{Display, Formatter, Result as FmtResult},
+    io,
+    sync::Arc,
+};
+
+/// An error from any stage of compilation or evaluation.
+#[derive(Debug)]
+pub enum Error {
+    /// Output could not be written.
+    Io(io::Error),
+
+    /// A number was divided by zero during evaluation.
+    DivisionByZero,
+
+    /// A type variable was used outside the type abstraction binding it.
+    EscapingTypeVariable {
+        /// The name of the type variable.
+        name: String,
+
+        /// The type abstraction.
+        span: Span,
+    },
+
+    /// A type would have to contain itself to make an expression well-typed.
+    InfiniteType {
+        /// The offending expression.
+        expr: String,
+
+        /// The location of the expression.
+        span: Span,
+
+        /// The type variable that would have to contain itself.
+        var: Arc<Type>,
+
+        /// The type the variable would have to be equal to.
+        ty: Arc<Type>,
+    },
+
+    /// An integer literal does not fit in 64 bits, or in a cell of the target being compiled to.
+    LiteralOutOfRange {
+        /// The location of the literal.
+        span: Span,
+    },
+
+    /// A module imported itself, possibly indirectly.
+    ImportCycle {
+        /// The path of the import that closed the cycle.
+        path: String,
+
+        /// The location of the import.
+        span: Span,
+    },
+
+    /// An imported module could not be read.
+    ImportFailed {
+        /// The path of the import.
+        path: String,
+
+        /// The location of the import.
+        span: Span,
+
+        /// The reason the module could not be read.
+        cause: io::Error,
+    },
+
+    /// An error in a module loaded by a `Loader`, along with the path and source code of that
+    /// module. Every module's errors are wrapped, including those of the module loaded first.
+    InModule {
+        /// The path of the module.
+        path: String,
+
+        /// The source code of the module.
+        src: String,
+
+        /// The error.
+        err: Box<Error>,
+    },
+
+    /// The source contained something that isn't a token.
+    InvalidToken {
+        /// The location of the invalid token.
+        span: Span,
+    },
+
+    /// The right-hand side of a `let rec` was not a lambda.
+    InvalidLetRec {
+        /// The right-hand side.
+        expr: String,
+
+        /// The location of the right-hand side.
+        span: Span,
+    },
+
+    /// A program did not define `main`.
+    MissingMain,
+
+    /// A value was applied as a function, but is not one.
+    NotAFunction {
+        /// The expression being applied.
+        expr: String,
+
+        /// The location of the expression.
+        span: Span,
+
+        /// The type of the expression.
+        ty: Arc<Type>,
+    },
+
+    /// A value was projected from as a pair, but is not one.
+    NotAPair {
+        /// The expression being projected from.
+        expr: String,
+
+        /// The location of the expression.
+        span: Span,
+
+        /// The type of the expression.
+        ty: Arc<Type>,
+    },
+
+    /// A value was used as a sum, but is not one.
+    NotASum {
+        /// The expression used as a sum.
+        expr: String,
+
+        /// The location of the expression.
+        span: Span,
+
+        /// The type of the expression.
+        ty: Arc<Type>,
+    },
+
+    /// A type was applied to an expression that is not a type abstraction.
+    NotPolymorphic {
+        /// The expression the type was applied to.
+        expr: String,
+
+        /// The location of the expression.
+        span: Span,
+
+        /// The type of the expression.
+        ty: Arc<Type>,
+    },
+
+    /// Evaluation reached a state with no applicable rule. This can only happen when evaluating
+    /// ill-typed code.
+    Stuck(String),
+
+    /// An expression had a different type than the one required of it.
+    TypeMismatch {
+        /// The offending expression.
+        expr: String,
+
+        /// The location of the expression.
+        span: Span,
+
+        /// The type that was required.
+        expected: Arc<Type>,
+
+        /// The type the expression actually has.
+        actual: Arc<Type>,
+    },
+
+    /// A variable was referenced outside of any binding of it.
+    UnboundVariable {
+        /// The name of the variable.
+        name: String,
+
+        /// The location of the reference.
+        span: Span,
+    },
+
+    /// A block comment was not closed before the end of the input.
+    UnterminatedComment {
+        /// The location of the comment.
+        span: Span,
+    },
+
+    /// The parser encountered a token it did not expect.
+    UnexpectedToken {
+        /// The location of the token. This is empty if the end of the input was reached.
+        span: Span,
+
+        /// The token, or `None` if the end of the input was reached.
+        token: Option<String>,
+
+        /// The tokens that would have been accepted instead.
+        expected: Vec<String>,
+    },
+}
+
+impl Display for Error {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        match self {
+            Error::Io(err) => write!(fmt, "{}", err),
+            Error::DivisionByZero => write!(fmt, "Division by zero"),
+            Error::EscapingTypeVariable { name, .. } => {
+                write!(fmt, "The type variable {} escapes its scope", name)
+            }
+            Error::InfiniteType { expr, var, ty, .. } => write!(
+                fmt,
+                "Cannot construct the infinite type {} = {} for {}",
+                var, ty, expr
+            ),
+            Error::LiteralOutOfRange { .. } => write!(fmt, "Integer literal out of range"),
+            Error::ImportCycle { path, .. } => write!(fmt, "Cyclic import of {}", path),
+            Error::ImportFailed { path, cause, .. } => {
+                write!(fmt, "Cannot import {}: {}", path, cause)
+            }
+            Error::InModule { path, err, .. } => write!(fmt, "{} (in {})", err, path),
+            Error::InvalidToken { .. } => write!(fmt, "Invalid token"),
+            Error::InvalidLetRec { expr, .. } => {
+                write!(
+                    fmt,
+                    "The right-hand side of a let rec must be a lambda: {}",
+                    expr
+                )
+            }
+            Error::MissingMain => write!(fmt, "The program does not define main"),
+            Error::NotAFunction { expr, ty, .. } => {
+                write!(fmt, "Not a function: {} (of type {})", expr, ty)
+            }
+            Error::NotAPair { expr, ty, .. } => {
+                write!(fmt, "Not a pair: {} (of type {})", expr, ty)
+            }
+            Error::NotASum { expr, ty, .. } => write!(fmt, "Not a sum: {} (of type {})", expr, ty),
+            Error::NotPolymorphic { expr, ty, .. } => {
+                write!(fmt, "Not polymorphic: {} (of type {})", expr, ty)
+            }
+            Error::Stuck(msg) => write!(fmt, "Evaluation got stuck: {}", msg),
+            Error::TypeMismatch {
+                expr,
+                expected,
+                actual,
+                ..
+            } => write!(
+                fmt,
+                "Expected {} to have type {}, but it has type {}",
+                expr, expected, actual
+            ),
+            Error::UnboundVariable { name, .. } => write!(fmt, "Not found: {}", name),
+            Error::UnterminatedComment { .. } => write!(fmt, "Unterminated block comment"),
+            Error::UnexpectedToken {
+                token, expected, ..
+            } => {
+                match token {
+                    Some(token) => write!(fmt, "Unexpected token {}", token)?,
+                    None => write!(fmt, "Unexpected end of input")?,
+                }
+                if !expected.is_empty() {
+                    write!(fmt, "; expected one of {}", expected.iter().join(", "))?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+impl Error {
+    /// Returns the location in the source code the error refers to, if any.
+    pub fn span(&self) -> Option<Span> {
+        match *self {
+            Error::EscapingTypeVariable { span, .. }
+            | Error::InfiniteType { span, .. }
+            | Error::LiteralOutOfRange { span }
+            | Error::ImportCycle { span, .. }
+            | Error::ImportFailed { span, .. }
+            | Error::InvalidToken { span }
+            | Error::InvalidLetRec { span, .. }
+            | Error::NotAFunction { span, .. }
+            | Error::NotAPair { span, .. }
+            | Error::NotASum { span, .. }
+            | Error::NotPolymorphic { span, .. }
+            | Error::TypeMismatch { span, .. }
+            | Error::UnboundVariable { span, .. }
+            | Error::UnterminatedComment { span }
+            | Error::UnexpectedToken { span, .. } => Some(span),
+            Error::DivisionByZero
+            | Error::InModule { .. }
+            | Error::Io(_)
+            | Error::MissingMain
+            | Error::Stuck(_) => None,
+        }
+    }
+
+    /// Renders the error as a diagnostic, showing the line of source code it refers to (if any)
+    /// with the offending part underlined.
+    pub fn render(&self, path: &str, src: &str) -> String {
+        if let Error::InModule { path, src, err } = self {
+            return err.render(path, src);
+        }
+
+        let mut out = format!("error: {}", self);
+        let span = match self.span() {
+            Some(span) => span,
+            None => return out,
+        };
+
+        let start = span.start.min(src.len());
+        let line_start = src[..start].rfind('\n').map(|i| i + 1).unwrap_or(0);
+        let line_end = src[start..]
+            .find('\n')
+            .map(|i| start + i)
+            .unwrap_or_else(|| src.len());
+        let end = span.end.max(start).min(line_end);
+        let line_num = src[..start].matches('\n').count() + 1;
+        let col = src[line_start..start].chars().count() + 1;
+
+        // Tabs are kept in the indentation of the underline, so that it lines up with the source.
+        let indent: String = src[line_start..start]
+            .chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let underline = "^".repeat(src[start..end].chars().count().max(1));
+
+        let gutter = " ".repeat(line_num.to_string().len());
+        out += &format!("\n{}--> {}:{}:{}", gutter, path, line_num, col);
+        out += &format!("\n{} |", gutter);
+        out += &format!("\n{} | {}", line_num, &src[line_start..line_end]);
+        out += &format!("\n{} | {}{}", gutter, indent, underline);
+        out
+    }
+
+    /// Converts an error from the parser. `eof` is the length of the input, which is used as the
+    /// location of an unexpected end of input. Errors from the lexer are passed through.
+    pub(crate) fn from_parse_error<T: Display>(
+        err: ParseError<usize, T, Error>,
+        eof: usize,
+    ) -> Error {
+        match err {
+            ParseError::InvalidToken { location } => Error::InvalidToken {
+                span: Span::new(location, location),
+            },
+            ParseError::UnrecognizedToken { token, expected } => match token {
+                Some((start, token, end)) => Error::UnexpectedToken {
+                    span: Span::new(start, end),
+                    token: Some(token.to_string()),
+                    expected,
+                },
+                None => Error::UnexpectedToken {
+                    span: Span::new(eof, eof),
+                    token: None,
+                    expected,
+                },
+            },
+            ParseError::ExtraToken {
+                token: (start, token, end),
+            } => Error::UnexpectedToken {
+                span: Span::new(start, end),
+                token: Some(token.to_string()),
+                expected: Vec::new(),
+            },
+            ParseError::User { error } => error,
+        }
+    }
+}
@@ -0,0 +1,277 @@
+//! The lexer used by the grammar.
+//!
+//! Comments are skipped like whitespace, but are recorded so they can be attached to the
+//! top-level items of a program. Line comments start with `--` or `//`, and block comments are
+//! delimited by `/*` and `*/` and may be nested.
+//!
+//! A `-` immediately followed by a digit starts a negative numeric literal, so `-1` is a number
+//! while `- 1` applies subtraction.
+
+use crate::{ast::Comment, Error, Span};
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+/// The keywords and operators of the grammar that look like names.
+const KEYWORDS: &[(&str, Tok<'static>)] = &[
+    ("*", Tok::Star),
+    ("+", Tok::Plus),
+    ("->", Tok::Arrow),
+    ("=", Tok::Equals),
+    ("=>", Tok::FatArrow),
+    ("bool", Tok::Bool),
+    ("case", Tok::Case),
+    ("def", Tok::Def),
+    ("else", Tok::Else),
+    ("false", Tok::False),
+    ("forall", Tok::Forall),
+    ("fst", Tok::Fst),
+    ("if", Tok::If),
+    ("import", Tok::Import),
+    ("in", Tok::In),
+    ("inl", Tok::Inl),
+    ("inr", Tok::Inr),
+    ("int", Tok::Int),
+    ("let", Tok::Let),
+    ("of", Tok::Of),
+    ("rec", Tok::Rec),
+    ("snd", Tok::Snd),
+    ("then", Tok::Then),
+    ("true", Tok::True),
+    ("unit", Tok::Unit),
+];
+
+/// The punctuation of the grammar.
+const SYMBOLS: &[(&str, Tok<'static>)] = &[
+    ("/\\", Tok::BigLambdaAscii),
+    ("\\", Tok::Backslash),
+    ("λ", Tok::Lambda),
+    ("Λ", Tok::BigLambda),
+    (":", Tok::Colon),
+    (".", Tok::Dot),
+    ("|", Tok::Bar),
+    (";", Tok::Semicolon),
+    ("[", Tok::LBracket),
+    ("]", Tok::RBracket),
+    ("(", Tok::LParen),
+    (")", Tok::RParen),
+    (",", Tok::Comma),
+];
+
+/// A token.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Tok<'s> {
+    /// A name.
+    Name(&'s str),
+
+    /// A numeric literal.
+    Num(&'s str),
+
+    /// A string literal, including the quotes.
+    Str(&'s str),
+
+    // Keywords and punctuation, as listed in KEYWORDS and SYMBOLS.
+    Star,
+    Plus,
+    Arrow,
+    Equals,
+    FatArrow,
+    Bool,
+    Case,
+    Def,
+    Else,
+    False,
+    Forall,
+    Fst,
+    If,
+    Import,
+    In,
+    Inl,
+    Inr,
+    Int,
+    Let,
+    Of,
+    Rec,
+    Snd,
+    Then,
+    True,
+    Unit,
+    BigLambdaAscii,
+    Backslash,
+    Lambda,
+    BigLambda,
+    Colon,
+    Dot,
+    Bar,
+    Semicolon,
+    LBracket,
+    RBracket,
+    LParen,
+    RParen,
+    Comma,
+}
+
+impl<'s> Display for Tok<'s> {
+    fn fmt(&self, fmt: &mut Formatter) -> FmtResult {
+        match self {
+            Tok::Name(s) | Tok::Num(s) | Tok::Str(s) => write!(fmt, "{}", s),
+            tok => {
+                let (s, _) = KEYWORDS
+                    .iter()
+                    .chain(SYMBOLS)
+                    .find(|(_, t)| t == tok)
+                    .unwrap();
+                write!(fmt, "{}", s)
+            }
+        }
+    }
+}
+
+/// A lexer over a source string.
+#[derive(Debug)]
+pub struct Lexer<'s> {
+    src: &'s str,
+    pos: usize,
+
+    /// The comments that have been skipped so far.
+    pub comments: Vec<Comment>,
+}
+
+impl<'s> Lexer<'s> {
+    /// Creates a lexer for the given source code.
+    pub fn new(src: &'s str) -> Lexer<'s> {
+        Lexer {
+            src,
+            pos: 0,
+            comments: Vec::new(),
+        }
+    }
+
+    fn rest(&self) -> &'s str {
+        &self.src[self.pos..]
+    }
+
+    /// Skips whitespace and comments.
+    fn skip(&mut self) -> Result<(), Error> {
+        loop {
+            let rest = self.rest();
+            let start = self.pos;
+            if let Some(c) = rest.chars().next().filter(|c| c.is_whitespace()) {
+                self.pos += c.len_utf8();
+            } else if rest.starts_with("--") || rest.starts_with("//") {
+                self.pos += rest.find('\n').unwrap_or(rest.len());
+                self.comment(start);
+            } else if rest.starts_with("/*") {
+                let mut depth = 0;
+                loop {
+                    let rest = self.rest();
+                    if rest.starts_with("/*") {
+                        depth += 1;
+                        self.pos += 2;
+                    } else if rest.starts_with("*/") {
+                        depth -= 1;
+                        self.pos += 2;
+                        if depth == 0 {
+                            break;
+                        }
+                    } else if let Some(c) = rest.chars().next() {
+                        self.pos += c.len_utf8();
+                    } else {
+                        return Err(Error::UnterminatedComment {
+                            span: Span::new(start, self.pos),
+                        });
+                    }
+                }
+                self.comment(start);
+            } else {
+                return Ok(());
+            }
+        }
+    }
+
+    fn comment(&mut self, start: usize) {
+        self.comments.push(Comment {
+            text: self.src[start..self.pos].to_string(),
+            span: Span::new(start, self.pos),
+        });
+    }
+
+    /// Lexes the token at the current position, which must not be at the end of the input.
+    fn token(&mut self) -> Result<Tok<'s>, Error> {
+        let rest = self.rest();
+        let start = self.pos;
+        let invalid = Error::InvalidToken {
+            span: Span::new(start, start),
+        };
+        let digits = rest.strip_prefix('-').unwrap_or(rest);
+
+        if let Some(&(s, tok)) = SYMBOLS.iter().find(|(s, _)| rest.starts_with(s)) {
+            self.pos += s.len();
+            Ok(tok)
+        } else if let Some(body) = rest.strip_prefix('"') {
+            let len = body.find('"').ok_or(invalid)? + 2;
+            self.pos += len;
+            Ok(Tok::Str(&rest[..len]))
+        } else if digits.starts_with(|c: char| c.is_ascii_digit()) {
+            let sign = rest.len() - digits.len();
+            let len = sign
+                + digits
+                    .find(|c: char| !c.is_ascii_digit())
+                    .unwrap_or(digits.len());
+            self.pos += len;
+            Ok(Tok::Num(&rest[..len]))
+        } else if rest.starts_with(is_name_start) {
+            // A comment may start immediately after a name.
+            let len = rest
+                .char_indices()
+                .find(|&(i, c)| {
+                    let tail = &rest[i..];
+                    !is_name_char(c)
+                        || tail.starts_with("--")
+                        || tail.starts_with("//")
+                        || tail.starts_with("/*")
+                })
+                .map(|(i, _)| i)
+                .unwrap_or(rest.len());
+            self.pos += len;
+            let name = &rest[..len];
+            Ok(KEYWORDS
+                .iter()
+                .find(|(s, _)| *s == name)
+                .map(|&(_, tok)| tok)
+                .unwrap_or(Tok::Name(name)))
+        } else {
+            Err(invalid)
+        }
+    }
+}
+
+impl<'s> Iterator for Lexer<'s> {
+    type Item = Result<(usize, Tok<'s>, usize), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Err(err) = self.skip() {
+            // Skip the rest of the input, so that lexing stops.
+            self.pos = self.src.len();
+            return Some(Err(err));
+        }
+        if self.pos == self.src.len() {
+            return None;
+        }
+
+        let start = self.pos;
+        match self.token() {
+            Ok(tok) => Some(Ok((start, tok, self.pos))),
+            Err(err) => {
+                self.pos = self.src.len();
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+fn is_name_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || "+*/<=>-".contains(c)
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_digit() || is_name_start(c)
+}
@@ -12,7 +12,14 @@ extern crate pretty_assertions;
 
 mod ast;
 mod cam;
-lalrpop_mod!(grammar);
+mod closure;
+mod error;
+mod lexer;
+lalrpop_mod!(
+    #[allow(clippy::all, unused_parens)]
+    grammar
+);
+mod loader;
 mod nameless;
 #[cfg(test)]
 mod tests;
@@ -20,16 +27,100 @@ mod tyck;
 
 #[cfg(test)]
 pub(crate) use crate::cam::StaticCombinator;
+use crate::nameless::remove_names;
 pub use crate::{
-    ast::{Expr, Type},
-    cam::eval::Combinator as DynamicCombinator,
+    ast::{Def, Expr, Program, Span, Type},
+    cam::{
+        eval::Combinator as DynamicCombinator,
+        machine::{Instruction, Machine, Value},
+        opt::Rule as OptimizationRule,
+    },
+    error::Error,
+    loader::{Loader, Module},
 };
 
+/// The cell widths, in bytes, that code can be compiled for.
+pub const CELL_WIDTHS: &[usize] = &[2, 4, 8];
+
+/// Options controlling compilation to Forth.
+#[derive(Clone, Debug)]
+pub struct CompileOptions {
+    /// The optimization rules applied to the combinators before they are compiled.
+    pub rules: Vec<OptimizationRule>,
+
+    /// Whether closures capture only their free variables, rather than their whole environment.
+    pub flat_closures: bool,
+
+    /// The width of a cell on the target, in bytes, which must be one of `CELL_WIDTHS`. Integer
+    /// literals that do not fit in a cell are rejected when compiling to Forth.
+    pub cell_width: usize,
+}
+
+impl Default for CompileOptions {
+    fn default() -> CompileOptions {
+        CompileOptions {
+            rules: Vec::new(),
+            flat_closures: false,
+            cell_width: 8,
+        }
+    }
+}
+
 impl Expr {
     /// Compiles the expression to a sequence of Forth definitions. The names generated are prefixed
     /// with the given string.
-    pub fn compile(&self, prefix: &str) -> Result<Vec<Vec<String>>, String> {
+    pub fn compile(&self, prefix: &str) -> Result<Vec<Vec<String>>, Error> {
         self.to_combinators()
             .map(|combinators| combinators.to_forth(prefix))
     }
 }
+
+impl Program {
+    /// Compiles the program to a sequence of Forth definitions. `main` becomes a word named
+    /// `fstlc-{prefix}-main`, which runs the program. The other top-level definitions are evaluated
+    /// in order when the Forth code is loaded, and their values are stored in variables named
+    /// `fstlc-{prefix}-{name}`, which references to them read.
+    pub fn compile(&self, prefix: &str) -> Result<Vec<Vec<String>>, Error> {
+        if self.defs.iter().all(|def| def.name != "main") {
+            return Err(Error::MissingMain);
+        }
+        self.compile_with(prefix, &[], &CompileOptions::default())
+    }
+
+    /// Like `compile`, but the program may refer to the given definitions from other modules,
+    /// which are given by their names and the prefixes of their modules, and is compiled with the
+    /// given options. The program need not define `main`.
+    pub fn compile_with(
+        &self,
+        prefix: &str,
+        imports: &[(&str, &str)],
+        options: &CompileOptions,
+    ) -> Result<Vec<Vec<String>>, Error> {
+        let mut decls = Vec::new();
+        let mut counter = 0;
+        let mut defs = imports.to_vec();
+        let bits = options.cell_width * 8;
+        for def in &self.defs {
+            if bits < 64 {
+                let max = (1 << (bits - 1)) - 1;
+                if let Some(&(_, span)) = def
+                    .body
+                    .literals()
+                    .iter()
+                    .find(|&&(n, _)| n < -max - 1 || n > max)
+                {
+                    return Err(Error::LiteralOutOfRange { span });
+                }
+            }
+            let expr = match def.body {
+                Expr::Lam(..) => def.bind(Expr::Var(def.name.clone(), def.span)),
+                _ => def.body.clone(),
+            };
+            remove_names(&defs, &mut Vec::new(), &expr)?
+                .to_combinator_with(options)
+                .to_forth_word(&def.name, prefix, &mut counter, &mut decls);
+            defs.push((&def.name, prefix));
+        }
+        Ok(decls)
+    }
+}
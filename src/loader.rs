@@ -0,0 +1,250 @@
+//! Loading programs split across several files.
+//!
+//! Each file is a module, which is parsed and typechecked once no matter how many modules import
+//! it. A module sees the definitions of the modules it directly imports, and its definitions are
+//! compiled to Forth words prefixed with the module's own prefix, so that the words of different
+//! modules never collide.
+
+use crate::{CompileOptions, Def, Error, Expr, Program, Type};
+use std::{
+    fs::read_to_string,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+/// A loaded module.
+#[derive(Debug)]
+pub struct Module {
+    /// The canonical path of the file the module was loaded from.
+    pub path: PathBuf,
+
+    /// The prefix of the Forth words generated for the module.
+    pub prefix: String,
+
+    /// The source code of the module.
+    pub src: String,
+
+    /// The parsed module.
+    pub program: Program,
+
+    /// The indices of the modules the module imports.
+    pub imports: Vec<usize>,
+
+    /// The types of the module's definitions.
+    pub types: Vec<(String, Arc<Type>)>,
+}
+
+/// A set of loaded modules. Modules are stored after all the modules they import.
+#[derive(Debug, Default)]
+pub struct Loader {
+    modules: Vec<Module>,
+    prefixes: Vec<String>,
+    loading: Vec<PathBuf>,
+}
+
+impl Loader {
+    /// Creates a loader with no modules loaded.
+    pub fn new() -> Loader {
+        Loader::default()
+    }
+
+    /// Returns the loaded modules.
+    pub fn modules(&self) -> &[Module] {
+        &self.modules
+    }
+
+    /// Loads the module at the given path and every module it imports, returning the index of the
+    /// module. The prefix defaults to the name of the file, without its extension. Errors inside a
+    /// module are wrapped in `Error::InModule`.
+    pub fn load(&mut self, path: &Path, prefix: Option<String>) -> Result<usize, Error> {
+        let path = path.canonicalize()?;
+        if let Some(index) = self.find(&path) {
+            return Ok(index);
+        }
+        let src = read_to_string(&path)?;
+        self.add(path, src, prefix)
+    }
+
+    /// Compiles every loaded module to a sequence of Forth definitions, imported modules first. The
+    /// module with the given index must define `main`. Errors inside a module are wrapped in
+    /// `Error::InModule`.
+    pub fn compile(
+        &self,
+        root: usize,
+        options: &CompileOptions,
+    ) -> Result<Vec<Vec<String>>, Error> {
+        if self.modules[root]
+            .program
+            .defs
+            .iter()
+            .all(|def| def.name != "main")
+        {
+            return Err(Error::MissingMain);
+        }
+
+        let mut decls = Vec::new();
+        for module in &self.modules {
+            let imports = self.imported_defs(module);
+            let module_decls = module
+                .program
+                .compile_with(&module.prefix, &imports, options)
+                .map_err(|err| Error::InModule {
+                    path: module.path.display().to_string(),
+                    src: module.src.clone(),
+                    err: Box::new(err),
+                })?;
+            decls.extend(module_decls);
+        }
+        Ok(decls)
+    }
+
+    /// Combines the definitions of every loaded module into a single program, for the evaluators.
+    /// Only the module with the given index keeps its `main`. So that names resolve to the same
+    /// definitions as when compiling, each definition is renamed to `{prefix}.{name}`, which no
+    /// variable can be named, except for `main`.
+    pub fn to_program(&self, root: usize) -> Program {
+        let qualify = |prefix: &str, name: &str| {
+            if prefix == self.modules[root].prefix && name == "main" {
+                name.to_string()
+            } else {
+                format!("{}.{}", prefix, name)
+            }
+        };
+
+        let mut defs = Vec::new();
+        for (i, module) in self.modules.iter().enumerate() {
+            let mut scope = self.imported_defs(module);
+            for def in &module.program.defs {
+                // A function refers to itself, rather than to an earlier definition of its name.
+                let is_fn = matches!(def.body, Expr::Lam(..));
+                if is_fn {
+                    scope.push((&def.name, &module.prefix));
+                }
+                let body = def.body.rename_free(&|name| {
+                    scope
+                        .iter()
+                        .rev()
+                        .find(|(n, _)| *n == name)
+                        .map(|(n, prefix)| qualify(prefix, n))
+                });
+                if !is_fn {
+                    scope.push((&def.name, &module.prefix));
+                }
+
+                if i == root || def.name != "main" {
+                    defs.push(Def {
+                        name: qualify(&module.prefix, &def.name),
+                        body,
+                        ..def.clone()
+                    });
+                }
+            }
+        }
+        Program {
+            imports: Vec::new(),
+            defs,
+        }
+    }
+
+    /// Returns the definitions of the modules the given module imports, along with their prefixes.
+    fn imported_defs<'a>(&'a self, module: &Module) -> Vec<(&'a str, &'a str)> {
+        module
+            .imports
+            .iter()
+            .flat_map(|&i| {
+                let import = &self.modules[i];
+                import
+                    .program
+                    .defs
+                    .iter()
+                    .map(move |def| (&def.name as &str, &import.prefix as &str))
+            })
+            .collect()
+    }
+
+    fn find(&self, path: &Path) -> Option<usize> {
+        self.modules.iter().position(|module| module.path == path)
+    }
+
+    fn add(&mut self, path: PathBuf, src: String, prefix: Option<String>) -> Result<usize, Error> {
+        let prefix = prefix.unwrap_or_else(|| {
+            let stem = path
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_else(|| "module".to_string());
+            let mut prefix = stem.clone();
+            let mut n = 1;
+            while self.prefixes.contains(&prefix) {
+                n += 1;
+                prefix = format!("{}-{}", stem, n);
+            }
+            prefix
+        });
+        self.prefixes.push(prefix.clone());
+
+        self.loading.push(path.clone());
+        let result = self.load_imports(&path, &src);
+        self.loading.pop();
+        let (program, imports, types) = match result {
+            Ok(module) => module,
+            Err(err @ Error::InModule { .. }) => return Err(err),
+            Err(err) => {
+                return Err(Error::InModule {
+                    path: path.display().to_string(),
+                    src,
+                    err: Box::new(err),
+                });
+            }
+        };
+
+        self.modules.push(Module {
+            path,
+            prefix,
+            src,
+            program,
+            imports,
+            types,
+        });
+        Ok(self.modules.len() - 1)
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn load_imports(
+        &mut self,
+        path: &Path,
+        src: &str,
+    ) -> Result<(Program, Vec<usize>, Vec<(String, Arc<Type>)>), Error> {
+        let program: Program = src.parse()?;
+        let dir = path.parent().unwrap_or_else(|| Path::new("."));
+
+        let mut imports = Vec::new();
+        let mut imported_types = Vec::new();
+        for import in &program.imports {
+            let failed = |cause| Error::ImportFailed {
+                path: import.path.clone(),
+                span: import.span,
+                cause,
+            };
+            let import_path = dir.join(&import.path).canonicalize().map_err(failed)?;
+            if self.loading.contains(&import_path) {
+                return Err(Error::ImportCycle {
+                    path: import.path.clone(),
+                    span: import.span,
+                });
+            }
+
+            let index = match self.find(&import_path) {
+                Some(index) => index,
+                None => {
+                    let src = read_to_string(&import_path).map_err(failed)?;
+                    self.add(import_path, src, None)?
+                }
+            };
+            imports.push(index);
+            imported_types.extend(self.modules[index].types.iter().cloned());
+        }
+
+        let types = program.tyck_defs(&imported_types)?;
+        Ok((program, imports, types))
+    }
+}
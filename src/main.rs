@@ -1,10 +1,14 @@
-use fstlc::{DynamicCombinator, Expr};
+use fstlc::{
+    CompileOptions, DynamicCombinator, Loader, Machine, OptimizationRule, Program, Type, Value,
+    CELL_WIDTHS,
+};
 use std::{
     error::Error,
-    fs::{read_to_string, write},
+    fs::write,
     io::{stdout, Write},
-    path::PathBuf,
+    path::{Path, PathBuf},
     process::exit,
+    sync::Arc,
 };
 use structopt::StructOpt;
 
@@ -15,7 +19,21 @@ fn main() {
         .verbosity(options.verbose)
         .init()
         .unwrap();
-    if let Err(err) = run(options.subcommand) {
+    let rules = if options.optimize {
+        OptimizationRule::ALL
+            .iter()
+            .filter(|rule| !options.disable.contains(rule))
+            .cloned()
+            .collect()
+    } else {
+        Vec::new()
+    };
+    let compile_options = CompileOptions {
+        rules,
+        flat_closures: options.flat_closures,
+        ..CompileOptions::default()
+    };
+    if let Err(err) = run(options.subcommand, &compile_options) {
         eprintln!("{}", err);
         exit(1);
     }
@@ -31,6 +49,18 @@ struct Options {
     #[structopt(short = "v", long = "verbose", parse(from_occurrences))]
     verbose: usize,
 
+    /// Optimizes the combinators before compiling or running them
+    #[structopt(short = "O", long = "optimize")]
+    optimize: bool,
+
+    /// Disables an optimization rule (access, assoc, beta, fst-pair, id, quote, or snd-pair)
+    #[structopt(long = "disable", number_of_values = 1)]
+    disable: Vec<OptimizationRule>,
+
+    /// Makes closures capture only their free variables, rather than their whole environment
+    #[structopt(long = "flat-closures")]
+    flat_closures: bool,
+
     /// The subcommand.
     #[structopt(subcommand)]
     subcommand: Command,
@@ -52,6 +82,20 @@ enum Command {
         /// The prefix used for generated definitions.
         #[structopt(short = "p", long = "prefix")]
         prefix: Option<String>,
+
+        /// The width of a cell on the target, in bytes (2, 4, or 8). Integers wrap at this width, and
+        /// literals that do not fit in a cell are rejected.
+        #[structopt(
+            short = "c",
+            long = "cell-width",
+            default_value = "8",
+            parse(try_from_str = "parse_cell_width")
+        )]
+        cell_width: usize,
+
+        /// The number of pairs the heap holds. Unreachable pairs are collected when it fills up.
+        #[structopt(short = "H", long = "heap-size", default_value = "65536")]
+        heap_size: usize,
     },
 
     /// Compiles the given file to combinators.
@@ -69,26 +113,57 @@ enum Command {
         #[structopt(parse(from_os_str))]
         input: PathBuf,
     },
+
+    /// Runs the given file on the Categorical Abstract Machine.
+    #[structopt(name = "run")]
+    Run {
+        /// The input file.
+        #[structopt(parse(from_os_str))]
+        input: PathBuf,
+
+        /// Prints the CAM code before running it.
+        #[structopt(short = "d", long = "dump")]
+        dump: bool,
+
+        /// Prints the number of pairs allocated after running. This includes a pair for each
+        /// top-level definition that is not a function, which the Forth code does not allocate.
+        #[structopt(short = "s", long = "stats")]
+        stats: bool,
+    },
 }
 
-fn run(command: Command) -> Result<(), Box<dyn Error>> {
+fn run(command: Command, options: &CompileOptions) -> Result<(), Box<dyn Error>> {
     match command {
         Command::Compile {
             input,
             output,
             prefix,
+            cell_width,
+            heap_size,
         } => {
-            let src = read_to_string(&input)?;
-            let expr: Expr = src.parse()?;
-            let ty = expr.tyck()?;
-            let prefix = prefix
-                .or(input.file_stem().map(|s| s.to_string_lossy().into_owned()))
-                .ok_or("Cannot determine prefix")?;
-
-            let mut forth = include_str!("prelude.f").to_string();
+            let (loader, root, ty) = load(&input, prefix)?;
+            let options = CompileOptions {
+                cell_width,
+                ..options.clone()
+            };
+
+            let mut forth = format!(
+                "{} constant fstlc-cell\n{} constant fstlc-heap-pairs\n",
+                cell_width, heap_size
+            );
+            forth += include_str!("prelude.f");
             forth.push('\n');
-            forth += &format!("\\ expr = {}\n\\ type = {}\n", expr, ty);
-            for decl in expr.compile(&prefix)? {
+            for module in loader.modules() {
+                forth += &format!("\\ module {} = {}\n", module.prefix, module.path.display());
+                for def in &module.program.defs {
+                    forth += &format!("\\ {}\n", def);
+                }
+            }
+            forth += &format!("\\ type = {}\n", ty);
+            for decl in loader
+                .compile(root, &options)
+                .map_err(|err| render(&loader, root, err))?
+            {
                 forth += &itertools::join(decl, " ");
                 forth.push('\n');
             }
@@ -102,18 +177,15 @@ fn run(command: Command) -> Result<(), Box<dyn Error>> {
         }
 
         Command::CompileToCombinators { input } => {
-            let src = read_to_string(&input)?;
-            let expr: Expr = src.parse()?;
-            expr.tyck()?;
-            println!("{}", expr.to_combinators()?);
+            let program = load_program(&input)?;
+            let combinator = program.to_expr()?.to_combinators_with(options)?;
+            println!("{}", combinator);
             Ok(())
         }
 
         Command::EvalCombinators { input } => {
-            let src = read_to_string(&input)?;
-            let expr: Expr = src.parse()?;
-            expr.tyck()?;
-            let combinator = expr.to_combinators()?;
+            let program = load_program(&input)?;
+            let combinator = program.to_expr()?.to_combinators_with(options)?;
             let mut last = DynamicCombinator::Apply(
                 Box::new(DynamicCombinator::from(combinator)),
                 Box::new(DynamicCombinator::Name("end-of-env".into())),
@@ -127,5 +199,67 @@ fn run(command: Command) -> Result<(), Box<dyn Error>> {
                 last = next;
             }
         }
+
+        Command::Run { input, dump, stats } => {
+            let program = load_program(&input)?;
+            let code = program
+                .to_expr()?
+                .to_combinators_with(options)?
+                .to_instructions();
+            if dump {
+                for (addr, instr) in code.iter().enumerate() {
+                    println!("{:4} {}", addr, instr);
+                }
+            }
+            let mut machine = Machine::new(code, Value::Num(0));
+            let value = machine.run()?;
+            println!("{}", value);
+            if stats {
+                println!("{} pairs allocated", machine.allocations());
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Loads and typechecks the given file and the modules it imports, returning the loader, the index
+/// of the file's module, and the type of `main`. Errors are rendered as diagnostics.
+fn load(
+    input: &Path,
+    prefix: Option<String>,
+) -> Result<(Loader, usize, Arc<Type>), Box<dyn Error>> {
+    let mut loader = Loader::new();
+    let root = loader
+        .load(input, prefix)
+        .map_err(|err| err.render(&input.display().to_string(), ""))?;
+    let ty = loader.modules()[root]
+        .types
+        .iter()
+        .rev()
+        .find(|(name, _)| name == "main")
+        .map(|(_, ty)| ty.clone())
+        .ok_or_else(|| render(&loader, root, fstlc::Error::MissingMain))?;
+    Ok((loader, root, ty))
+}
+
+/// Parses a cell width, which must be one of `CELL_WIDTHS`.
+fn parse_cell_width(s: &str) -> Result<usize, String> {
+    let width = s.parse::<usize>().map_err(|err| err.to_string())?;
+    if CELL_WIDTHS.contains(&width) {
+        Ok(width)
+    } else {
+        Err(format!("Unsupported cell width: {}", width))
     }
 }
+
+/// Loads the given file like `load`, combining its definitions with those of the modules it imports.
+fn load_program(input: &Path) -> Result<Program, Box<dyn Error>> {
+    let (loader, root, _) = load(input, None)?;
+    Ok(loader.to_program(root))
+}
+
+/// Renders an error in the module with the given index as a diagnostic.
+fn render(loader: &Loader, index: usize, err: fstlc::Error) -> String {
+    let module = &loader.modules()[index];
+    err.render(&module.path.display().to_string(), &module.src)
+}
@@ -1,53 +1,195 @@
-use crate::Expr;
+use crate::{tyck::BUILTINS, Error, Expr};
 
 /// A nameless expression.
-#[derive(Debug, Display, PartialEq)]
+#[derive(Clone, Debug, Display, PartialEq)]
 pub enum NamelessExpr {
     /// Function application.
     #[display(fmt = "({} {})", _0, _1)]
     App(Box<NamelessExpr>, Box<NamelessExpr>),
 
+    /// A boolean literal.
+    #[display(fmt = "{}", _0)]
+    Bool(bool),
+
+    /// Case analysis on a sum. The value inside the sum is bound in both branches.
+    #[display(fmt = "(case {} of inl => {} | inr => {})", _0, _1, _2)]
+    Case(Box<NamelessExpr>, Box<NamelessExpr>, Box<NamelessExpr>),
+
+    /// A flat closure, whose environment holds only the given variables. In the body, `$0` is the
+    /// argument and `$(j + 1)` is the `j`th captured variable.
+    #[display(
+        fmt = "λ[{}]{}",
+        "itertools::join(_0.iter().map(|i| format!(\"${}\", i)), \", \")",
+        _1
+    )]
+    Closure(Vec<usize>, Box<NamelessExpr>),
+
+    /// A reference to a top-level definition, given by the prefix of its module and its name.
+    #[display(fmt = "{}.{}", _0, _1)]
+    Def(String, String),
+
+    /// The first component of a pair.
+    #[display(fmt = "(fst {})", _0)]
+    Fst(Box<NamelessExpr>),
+
+    /// A conditional.
+    #[display(fmt = "(if {} then {} else {})", _0, _1, _2)]
+    If(Box<NamelessExpr>, Box<NamelessExpr>, Box<NamelessExpr>),
+
+    /// The left injection into a sum.
+    #[display(fmt = "(inl {})", _0)]
+    Inl(Box<NamelessExpr>),
+
+    /// The right injection into a sum.
+    #[display(fmt = "(inr {})", _0)]
+    Inr(Box<NamelessExpr>),
+
     /// Function abstraction.
     #[display(fmt = "λ{}", _0)]
     Lam(Box<NamelessExpr>),
 
+    /// A let binding. The bound expression is in scope in the body.
+    #[display(fmt = "(let {} in {})", _0, _1)]
+    Let(Box<NamelessExpr>, Box<NamelessExpr>),
+
+    /// A recursive function binding. The first expression is the body of the function, in which
+    /// the function itself is bound outside its argument. The function is in scope in the second
+    /// expression.
+    #[display(fmt = "(let rec λ{} in {})", _0, _1)]
+    LetRec(Box<NamelessExpr>, Box<NamelessExpr>),
+
     /// A literal value.
     #[display(fmt = "{}", _0)]
-    Lit(u32),
+    Lit(i64),
 
     /// A globally named function.
     #[display(fmt = "{}", _0)]
     Nam(String),
 
+    /// A recursive flat closure, whose environment holds only the given variables. In the body,
+    /// `$0` is the argument, `$1` is the closure itself, and `$(j + 2)` is the `j`th captured
+    /// variable.
+    #[display(
+        fmt = "μλ[{}]{}",
+        "itertools::join(_0.iter().map(|i| format!(\"${}\", i)), \", \")",
+        _1
+    )]
+    RecClosure(Vec<usize>, Box<NamelessExpr>),
+
+    /// Sequencing. The first expression is evaluated only for its effects.
+    #[display(fmt = "({}; {})", _0, _1)]
+    Seq(Box<NamelessExpr>, Box<NamelessExpr>),
+
+    /// The second component of a pair.
+    #[display(fmt = "(snd {})", _0)]
+    Snd(Box<NamelessExpr>),
+
+    /// A pair.
+    #[display(fmt = "({}, {})", _0, _1)]
+    Tuple(Box<NamelessExpr>, Box<NamelessExpr>),
+
+    /// The unit value.
+    #[display(fmt = "()")]
+    Unit,
+
     /// A variable reference.
     #[display(fmt = "${}", _0)]
     Var(usize),
 }
 
-pub fn remove_names<'e>(scope: &mut Vec<&'e str>, expr: &'e Expr) -> Result<NamelessExpr, String> {
-    match expr {
-        Expr::App(l, r) => Ok(NamelessExpr::App(
-            Box::new(remove_names(scope, l)?),
-            Box::new(remove_names(scope, r)?),
+/// Replaces the variables in an expression with de Bruijn indices. `defs` are the names of the
+/// top-level definitions in scope, along with the prefixes of the modules defining them. Later
+/// definitions shadow earlier ones.
+pub fn remove_names<'e>(
+    defs: &[(&str, &str)],
+    scope: &mut Vec<&'e str>,
+    expr: &'e Expr,
+) -> Result<NamelessExpr, Error> {
+    match expr.erase() {
+        Expr::App(l, r, _) => Ok(NamelessExpr::App(
+            Box::new(remove_names(defs, scope, l)?),
+            Box::new(remove_names(defs, scope, r)?),
         )),
-        Expr::Lam(s, _, e) => {
+        Expr::Bool(b, _) => Ok(NamelessExpr::Bool(*b)),
+        Expr::Case(e, x, a, y, b, _) => {
+            let e = remove_names(defs, scope, e)?;
+            scope.push(x);
+            let a = remove_names(defs, scope, a)?;
+            scope.pop();
+            scope.push(y);
+            let b = remove_names(defs, scope, b)?;
+            scope.pop();
+            Ok(NamelessExpr::Case(Box::new(e), Box::new(a), Box::new(b)))
+        }
+        Expr::Fst(e, _) => Ok(NamelessExpr::Fst(Box::new(remove_names(defs, scope, e)?))),
+        Expr::If(c, a, b, _) => Ok(NamelessExpr::If(
+            Box::new(remove_names(defs, scope, c)?),
+            Box::new(remove_names(defs, scope, a)?),
+            Box::new(remove_names(defs, scope, b)?),
+        )),
+        Expr::Inl(_, e, _) => Ok(NamelessExpr::Inl(Box::new(remove_names(defs, scope, e)?))),
+        Expr::Inr(_, e, _) => Ok(NamelessExpr::Inr(Box::new(remove_names(defs, scope, e)?))),
+        Expr::Lam(s, _, e, _) => {
             scope.push(s);
-            let e = remove_names(scope, e)?;
+            let e = remove_names(defs, scope, e)?;
             scope.pop();
             Ok(NamelessExpr::Lam(Box::new(e)))
         }
-        Expr::Lit(n) => Ok(NamelessExpr::Lit(*n)),
-        Expr::Var(n) => {
+        Expr::Let(s, _, e1, e2, _) => {
+            let e1 = remove_names(defs, scope, e1)?;
+            scope.push(s);
+            let e2 = remove_names(defs, scope, e2)?;
+            scope.pop();
+            Ok(NamelessExpr::Let(Box::new(e1), Box::new(e2)))
+        }
+        Expr::LetRec(s, _, e1, e2, _) => {
+            scope.push(s);
+            let b = match &**e1 {
+                Expr::Lam(x, _, b, _) => {
+                    scope.push(x);
+                    let b = remove_names(defs, scope, b)?;
+                    scope.pop();
+                    b
+                }
+                e1 => {
+                    return Err(Error::InvalidLetRec {
+                        expr: e1.to_string(),
+                        span: e1.span(),
+                    })
+                }
+            };
+            let e2 = remove_names(defs, scope, e2)?;
+            scope.pop();
+            Ok(NamelessExpr::LetRec(Box::new(b), Box::new(e2)))
+        }
+        Expr::Lit(n, _) => Ok(NamelessExpr::Lit(*n)),
+        Expr::Seq(e1, e2, _) => Ok(NamelessExpr::Seq(
+            Box::new(remove_names(defs, scope, e1)?),
+            Box::new(remove_names(defs, scope, e2)?),
+        )),
+        Expr::Snd(e, _) => Ok(NamelessExpr::Snd(Box::new(remove_names(defs, scope, e)?))),
+        Expr::Tuple(l, r, _) => Ok(NamelessExpr::Tuple(
+            Box::new(remove_names(defs, scope, l)?),
+            Box::new(remove_names(defs, scope, r)?),
+        )),
+        Expr::TyApp(..) | Expr::TyLam(..) => unreachable!("types should have been erased"),
+        Expr::Unit(_) => Ok(NamelessExpr::Unit),
+        Expr::Var(n, span) => {
             let r = scope
                 .iter()
                 .rposition(|n2| n == n2)
                 .map(|n| NamelessExpr::Var(scope.len() - n - 1));
-            match r {
-                Some(e) => Ok(e),
-                None => match &**n {
-                    "+" | "-" | "*" | "/" | "mod" | "print" => Ok(NamelessExpr::Nam(n.clone())),
-                    n => Err(format!("Not found: {}", n)),
-                },
+            let def = defs.iter().rev().find(|(d, _)| d == n);
+            match (r, def) {
+                (Some(e), _) => Ok(e),
+                (None, Some((_, m))) => Ok(NamelessExpr::Def(m.to_string(), n.clone())),
+                (None, None) if BUILTINS.iter().any(|(b, _)| b == n) => {
+                    Ok(NamelessExpr::Nam(n.clone()))
+                }
+                (None, None) => Err(Error::UnboundVariable {
+                    name: n.clone(),
+                    span: *span,
+                }),
             }
         }
     }
@@ -1,4 +1,171 @@
-: fstlc-make-pair ( x y -- x,y ) here rot , swap , ;
+\ FSTLC-CELL is the width of a cell in bytes, and FSTLC-HEAP-PAIRS is the number of pairs the heap
+\ holds. They are defined by the compiler before this prelude.
 : fstlc-fst ( x,y -- x ) @ ;
-: fstlc-snd ( x,y -- y ) 4 + @ ;
-: fstlc-app todo ;
+: fstlc-snd ( x,y -- y ) fstlc-cell + @ ;
+: fstlc-set-snd ( y x,_ -- ) fstlc-cell + ! ;
+
+\ Pairs are allocated from a fixed heap. Free pairs are linked into a list through their first
+\ cells, and the list is 0-terminated.
+fstlc-cell 2 * constant fstlc-pair-size
+create fstlc-heap fstlc-heap-pairs fstlc-pair-size * allot
+variable fstlc-free
+
+\ When the free list runs out, the heap is collected by a conservative mark-sweep collector. The
+\ roots are the cells on the data stack, which is where compiled code keeps every live value, and
+\ the values of top-level definitions (see FSTLC-DEF). Any cell that holds the address of a pair
+\ in the heap is treated as a pointer, so a number that happens to look like one may keep garbage
+\ alive, but live pairs are never freed. Pairs are never moved, since a cell that might be a
+\ number cannot be updated.
+create fstlc-marks fstlc-heap-pairs allot
+create fstlc-mark-stack fstlc-heap-pairs fstlc-cell * allot
+variable fstlc-mark-depth
+
+: fstlc-heap-pair? ( x -- flag )
+  fstlc-heap - dup fstlc-heap-pairs fstlc-pair-size * u<
+  swap fstlc-pair-size mod 0= and ;
+: fstlc-mark-byte ( x,y -- addr ) fstlc-heap - fstlc-pair-size / fstlc-marks + ;
+
+\ Marks the cell and pushes it onto the mark stack, if it is an unmarked pair. Since each pair is
+\ pushed at most once, the mark stack cannot overflow.
+: fstlc-mark-cell ( x -- )
+  dup fstlc-heap-pair? 0= if drop exit then
+  dup fstlc-mark-byte dup c@ if 2drop exit then
+  1 swap c!
+  fstlc-mark-stack fstlc-mark-depth @ fstlc-cell * + !
+  1 fstlc-mark-depth +! ;
+
+: fstlc-mark ( x -- )
+  fstlc-mark-cell
+  begin fstlc-mark-depth @ while
+    -1 fstlc-mark-depth +!
+    fstlc-mark-stack fstlc-mark-depth @ fstlc-cell * + @
+    dup fstlc-fst fstlc-mark-cell fstlc-snd fstlc-mark-cell
+  repeat ;
+
+\ The values of top-level definitions other than main are computed once, when the program is
+\ loaded, and are also roots. Each is stored in a cell followed by a link to the previous one.
+variable fstlc-roots
+0 fstlc-roots !
+: fstlc-def ( x "name" -- ) create here swap , fstlc-roots @ , fstlc-roots ! ;
+
+: fstlc-mark-roots ( -- )
+  fstlc-roots @ begin dup while dup @ fstlc-mark fstlc-cell + @ repeat drop ;
+
+\ Frees every unmarked pair, and clears the marks.
+: fstlc-sweep ( -- )
+  0 fstlc-free !
+  fstlc-heap-pairs 0 ?do
+    fstlc-marks i + dup c@ if
+      0 swap c!
+    else
+      drop fstlc-heap i fstlc-pair-size * +
+      fstlc-free @ over ! fstlc-free !
+    then
+  loop ;
+
+: fstlc-gc ( -- )
+  depth 0 ?do i pick fstlc-mark loop
+  fstlc-mark-roots
+  fstlc-sweep
+  fstlc-free @ 0= if ." Out of memory" cr abort then ;
+
+fstlc-marks fstlc-heap-pairs 0 fill
+fstlc-sweep
+
+: fstlc-make-pair ( x y -- x,y )
+  fstlc-free @ 0= if fstlc-gc then
+  fstlc-free @ dup fstlc-fst fstlc-free !
+  tuck fstlc-cell + ! tuck ! ;
+
+\ Access(n)z = (Snd∘Fst∘...∘Fst)z, with n Fsts, is the variable with De Bruijn index n in the
+\ environment z. It is compiled to n FSTLC-ACCESS.
+: fstlc-access ( z n -- v ) begin dup while swap fstlc-fst swap 1- repeat drop fstlc-snd ;
+
+\ A closure Λ(x)y is represented as the pair (xt, y), where xt is the execution token of the word
+\ compiled from x.
+: fstlc-make-closure ( y xt -- xt,y ) swap fstlc-make-pair ;
+
+\ Rec(x)z = w, where w = (z, Λ(x)w). The pair is allocated first, so the closure can refer to it.
+: fstlc-rec ( z xt -- w )
+  swap 0 fstlc-make-pair
+  dup rot fstlc-make-closure
+  over fstlc-set-snd ;
+
+\ App(Λ(x)y, z) = x(y, z)
+: fstlc-app ( Λ(x)y,z -- x(y,z) )
+  dup fstlc-snd swap fstlc-fst
+  dup fstlc-snd rot fstlc-make-pair
+  swap fstlc-fst execute ;
+
+\ Branch(x, y)(z, c) is compiled to FSTLC-BRANCH IF x ELSE y THEN. Booleans are Forth flags, i.e.
+\ true is -1 and false is 0.
+: fstlc-branch ( z,c -- z c ) dup fstlc-fst swap fstlc-snd ;
+
+\ A value of a sum type is a tagged cell, i.e. the pair (t, v), where the tag t is -1 for inl and 0
+\ for inr.
+: fstlc-inl ( v -- -1,v ) -1 swap fstlc-make-pair ;
+: fstlc-inr ( v -- 0,v ) 0 swap fstlc-make-pair ;
+
+\ Case(x, y)(z, (t, v)) is compiled to FSTLC-CASE IF x ELSE y THEN, where x and y are applied to
+\ (z, v).
+: fstlc-case ( z,(t,v) -- z,v t )
+  dup fstlc-fst swap fstlc-snd
+  dup fstlc-snd swap fstlc-fst
+  rot rot fstlc-make-pair swap ;
+
+\ Builtins are closures whose code performs the primitive operation. Binary builtins are curried,
+\ so applying one to its first argument returns a closure over that argument.
+: fstlc-binop-args ( (e,x),y -- x y ) dup fstlc-snd swap fstlc-fst fstlc-snd swap ;
+
+\ Integers are signed cells, and arithmetic wraps at the cell width. Division by zero aborts.
+\ Division truncates towards zero, and mod takes the sign of the dividend, whichever way the
+\ Forth system rounds / and mod, so they are built on SM/REM.
+: fstlc-check-divisor ( x y -- x y ) dup 0= if ." Division by zero" cr abort then ;
+
+: fstlc-+-2 ( (e,x),y -- x+y ) fstlc-binop-args + ;
+: fstlc-+-1 ( e,x -- clo ) ' fstlc-+-2 fstlc-make-closure ;
+: fstlc-builtin-+ ( -- clo ) 0 ' fstlc-+-1 fstlc-make-closure ;
+
+: fstlc---2 ( (e,x),y -- x-y ) fstlc-binop-args - ;
+: fstlc---1 ( e,x -- clo ) ' fstlc---2 fstlc-make-closure ;
+: fstlc-builtin-- ( -- clo ) 0 ' fstlc---1 fstlc-make-closure ;
+
+: fstlc-*-2 ( (e,x),y -- x*y ) fstlc-binop-args * ;
+: fstlc-*-1 ( e,x -- clo ) ' fstlc-*-2 fstlc-make-closure ;
+: fstlc-builtin-* ( -- clo ) 0 ' fstlc-*-1 fstlc-make-closure ;
+
+: fstlc-/-2 ( (e,x),y -- x/y ) fstlc-binop-args fstlc-check-divisor >r s>d r> sm/rem nip ;
+: fstlc-/-1 ( e,x -- clo ) ' fstlc-/-2 fstlc-make-closure ;
+: fstlc-builtin-/ ( -- clo ) 0 ' fstlc-/-1 fstlc-make-closure ;
+
+: fstlc-mod-2 ( (e,x),y -- x%y ) fstlc-binop-args fstlc-check-divisor >r s>d r> sm/rem drop ;
+: fstlc-mod-1 ( e,x -- clo ) ' fstlc-mod-2 fstlc-make-closure ;
+: fstlc-builtin-mod ( -- clo ) 0 ' fstlc-mod-1 fstlc-make-closure ;
+
+\ The unit value is represented as 0, although it is dropped rather than stored wherever possible.
+: fstlc-print-1 ( e,x -- () ) fstlc-snd . 0 ;
+: fstlc-builtin-print ( -- clo ) 0 ' fstlc-print-1 fstlc-make-closure ;
+
+: fstlc-=-2 ( (e,x),y -- x=y ) fstlc-binop-args = ;
+: fstlc-=-1 ( e,x -- clo ) ' fstlc-=-2 fstlc-make-closure ;
+: fstlc-builtin-= ( -- clo ) 0 ' fstlc-=-1 fstlc-make-closure ;
+
+: fstlc-<>-2 ( (e,x),y -- x<>y ) fstlc-binop-args <> ;
+: fstlc-<>-1 ( e,x -- clo ) ' fstlc-<>-2 fstlc-make-closure ;
+: fstlc-builtin-<> ( -- clo ) 0 ' fstlc-<>-1 fstlc-make-closure ;
+
+: fstlc-<-2 ( (e,x),y -- x<y ) fstlc-binop-args < ;
+: fstlc-<-1 ( e,x -- clo ) ' fstlc-<-2 fstlc-make-closure ;
+: fstlc-builtin-< ( -- clo ) 0 ' fstlc-<-1 fstlc-make-closure ;
+
+: fstlc-<=-2 ( (e,x),y -- x<=y ) fstlc-binop-args > 0= ;
+: fstlc-<=-1 ( e,x -- clo ) ' fstlc-<=-2 fstlc-make-closure ;
+: fstlc-builtin-<= ( -- clo ) 0 ' fstlc-<=-1 fstlc-make-closure ;
+
+: fstlc->-2 ( (e,x),y -- x>y ) fstlc-binop-args > ;
+: fstlc->-1 ( e,x -- clo ) ' fstlc->-2 fstlc-make-closure ;
+: fstlc-builtin-> ( -- clo ) 0 ' fstlc->-1 fstlc-make-closure ;
+
+: fstlc->=-2 ( (e,x),y -- x>=y ) fstlc-binop-args < 0= ;
+: fstlc->=-1 ( e,x -- clo ) ' fstlc->=-2 fstlc-make-closure ;
+: fstlc-builtin->= ( -- clo ) 0 ' fstlc->=-1 fstlc-make-closure ;
@@ -1,8 +1,9 @@
 use crate::{
     nameless::{remove_names, NamelessExpr},
-    DynamicCombinator, Expr, StaticCombinator, Type,
+    CompileOptions, DynamicCombinator, Error, Expr, Instruction, Loader, Machine, OptimizationRule,
+    Program, Span, StaticCombinator, Type, Value,
 };
-use std::sync::Arc;
+use std::{fs, path::Path, rc::Rc, sync::Arc};
 
 /// Compiles `id 1337`, checking the state through each step of the process.
 #[test]
@@ -15,15 +16,17 @@ fn id_1337() {
         Expr::App(
             Box::new(Expr::Lam(
                 "x".to_string(),
-                Arc::new(Type::Int),
-                Box::new(Expr::Var("x".to_string())),
+                Some(Arc::new(Type::Int)),
+                Box::new(Expr::Var("x".to_string(), Span::new(10, 11))),
+                Span::new(1, 11),
             )),
-            Box::new(Expr::Lit(1337)),
+            Box::new(Expr::Lit(1337, Span::new(13, 17))),
+            Span::new(0, 17),
         )
     );
     assert_eq!(expr.to_string(), "((λx:int. x) 1337)");
 
-    let nameless = remove_names(&mut Vec::new(), &expr).unwrap();
+    let nameless = remove_names(&[], &mut Vec::new(), &expr).unwrap();
     assert_eq!(
         nameless,
         NamelessExpr::App(
@@ -56,6 +59,7 @@ fn id_1337() {
                 "DUP",
                 "'",
                 "fstlc-lambda-test-0",
+                "FSTLC-MAKE-CLOSURE",
                 "SWAP",
                 "DROP",
                 "1337",
@@ -85,7 +89,6 @@ fn id_1337() {
 }
 
 /// Compiles `const (id 42) 137`, checking the state through each step of the process.
-#[ignore]
 #[test]
 fn const_id_42_137() {
     const SRC: &str = "(λx:int. λy:int. x) ((λx:int. x) 42) 137";
@@ -97,23 +100,29 @@ fn const_id_42_137() {
             Box::new(Expr::App(
                 Box::new(Expr::Lam(
                     "x".to_string(),
-                    Arc::new(Type::Int),
+                    Some(Arc::new(Type::Int)),
                     Box::new(Expr::Lam(
                         "y".to_string(),
-                        Arc::new(Type::Int),
-                        Box::new(Expr::Var("x".to_string())),
+                        Some(Arc::new(Type::Int)),
+                        Box::new(Expr::Var("x".to_string(), Span::new(19, 20))),
+                        Span::new(10, 20),
                     )),
+                    Span::new(1, 20),
                 )),
                 Box::new(Expr::App(
                     Box::new(Expr::Lam(
                         "x".to_string(),
-                        Arc::new(Type::Int),
-                        Box::new(Expr::Var("x".to_string())),
+                        Some(Arc::new(Type::Int)),
+                        Box::new(Expr::Var("x".to_string(), Span::new(33, 34))),
+                        Span::new(24, 34),
                     )),
-                    Box::new(Expr::Lit(42))
+                    Box::new(Expr::Lit(42, Span::new(36, 38))),
+                    Span::new(23, 38),
                 )),
+                Span::new(0, 39),
             )),
-            Box::new(Expr::Lit(137)),
+            Box::new(Expr::Lit(137, Span::new(40, 43))),
+            Span::new(0, 43),
         )
     );
     assert_eq!(
@@ -121,7 +130,7 @@ fn const_id_42_137() {
         "(((λx:int. (λy:int. x)) ((λx:int. x) 42)) 137)"
     );
 
-    let nameless = remove_names(&mut Vec::new(), &expr).unwrap();
+    let nameless = remove_names(&[], &mut Vec::new(), &expr).unwrap();
     assert_eq!(
         nameless,
         NamelessExpr::App(
@@ -148,10 +157,7 @@ fn const_id_42_137() {
                     Box::new(StaticCombinator::App),
                     Box::new(StaticCombinator::Pair(
                         Box::new(StaticCombinator::Lam(Box::new(StaticCombinator::Lam(
-                            Box::new(StaticCombinator::Com(
-                                Box::new(StaticCombinator::Snd),
-                                Box::new(StaticCombinator::Fst),
-                            )),
+                            Box::new(StaticCombinator::Access(1)),
                         )))),
                         Box::new(StaticCombinator::Com(
                             Box::new(StaticCombinator::App),
@@ -168,5 +174,1308 @@ fn const_id_42_137() {
     );
 
     let forth = combinator.to_forth("test");
-    assert_eq!(forth, &[[":", "fstlc-test-main", "0", ";"]]);
+    assert_eq!(
+        forth,
+        &[
+            &[":", "fstlc-lambda-test-0", "FSTLC-FST", "FSTLC-SND", ";"] as &[_],
+            &[
+                ":",
+                "fstlc-lambda-test-1",
+                "'",
+                "fstlc-lambda-test-0",
+                "FSTLC-MAKE-CLOSURE",
+                ";"
+            ] as &[_],
+            &[":", "fstlc-lambda-test-2", "FSTLC-SND", ";"] as &[_],
+            &[
+                ":",
+                "fstlc-test-main",
+                "0",
+                "DUP",
+                "DUP",
+                "'",
+                "fstlc-lambda-test-1",
+                "FSTLC-MAKE-CLOSURE",
+                "SWAP",
+                "DUP",
+                "'",
+                "fstlc-lambda-test-2",
+                "FSTLC-MAKE-CLOSURE",
+                "SWAP",
+                "DROP",
+                "42",
+                "FSTLC-MAKE-PAIR",
+                "FSTLC-APP",
+                "FSTLC-MAKE-PAIR",
+                "FSTLC-APP",
+                "SWAP",
+                "DROP",
+                "137",
+                "FSTLC-MAKE-PAIR",
+                "FSTLC-APP",
+                ";"
+            ] as &[_],
+        ]
+    );
+
+    let combinator =
+        DynamicCombinator::Apply(combinator.into(), Box::new(DynamicCombinator::Num(0)));
+    assert_eq!(combinator.eval(), DynamicCombinator::Num(42));
+
+    let mut machine = Machine::new(expr.to_instructions().unwrap(), Value::Num(0));
+    assert_eq!(machine.run().unwrap(), Value::Num(42));
+}
+
+/// Compiles `print 42`, checking that builtins are emitted as closures from the prelude.
+#[test]
+fn print_42() {
+    const SRC: &str = "print 42";
+
+    let expr = SRC.parse::<Expr>().unwrap();
+    let forth = expr.compile("test").unwrap();
+    assert_eq!(
+        forth,
+        &[&[
+            ":",
+            "fstlc-test-main",
+            "0",
+            "DUP",
+            "DROP",
+            "FSTLC-BUILTIN-PRINT",
+            "SWAP",
+            "DROP",
+            "42",
+            "FSTLC-MAKE-PAIR",
+            "FSTLC-APP",
+            ";"
+        ]]
+    );
+}
+
+/// Evaluates `id id 5`, where the result of an application is itself applied.
+#[test]
+fn id_id_5() {
+    const SRC: &str = "(λx:int -> int. x) (λx:int. x) 5";
+
+    let expr = SRC.parse::<Expr>().unwrap();
+    let combinator = DynamicCombinator::Apply(
+        expr.to_combinators().unwrap().into(),
+        Box::new(DynamicCombinator::Num(0)),
+    );
+    assert_eq!(combinator.eval(), DynamicCombinator::Num(5));
+}
+
+/// Evaluates `print (+ 1 2)`, checking the output of `print`.
+#[test]
+fn print_plus_1_2() {
+    const SRC: &str = "print (+ 1 2)";
+
+    let expr = SRC.parse::<Expr>().unwrap();
+    let combinator = DynamicCombinator::Apply(
+        expr.to_combinators().unwrap().into(),
+        Box::new(DynamicCombinator::Num(0)),
+    );
+    let mut out = Vec::new();
+    assert_eq!(combinator.eval_with(&mut out), DynamicCombinator::Unit);
+    assert_eq!(out, b"3\n");
+
+    let mut machine = Machine::new(expr.to_instructions().unwrap(), Value::Num(0));
+    let mut out = Vec::new();
+    assert_eq!(machine.run_with(&mut out).unwrap(), Value::Unit);
+    assert_eq!(out, b"3\n");
+}
+
+/// Evaluates a partially applied builtin passed as an argument.
+#[test]
+fn partial_builtin() {
+    const SRC: &str = "(λf:int -> int. f 10) (- 15)";
+
+    let expr = SRC.parse::<Expr>().unwrap();
+    let combinator = DynamicCombinator::Apply(
+        expr.to_combinators().unwrap().into(),
+        Box::new(DynamicCombinator::Num(0)),
+    );
+    assert_eq!(combinator.eval(), DynamicCombinator::Num(5));
+}
+
+/// Runs `id 1337` on the CAM.
+#[test]
+fn cam_id_1337() {
+    const SRC: &str = "(λx:int. x) 1337";
+
+    let expr = SRC.parse::<Expr>().unwrap();
+    let code = expr.to_instructions().unwrap();
+    assert_eq!(
+        code,
+        &[
+            Instruction::Push,
+            Instruction::Cur(7),
+            Instruction::Swap,
+            Instruction::Quote(Value::Num(1337)),
+            Instruction::Cons,
+            Instruction::App,
+            Instruction::Return,
+            Instruction::Cdr,
+            Instruction::Return,
+        ]
+    );
+
+    let mut machine = Machine::new(code, Value::Num(0));
+    assert_eq!(machine.run().unwrap(), Value::Num(1337));
+}
+
+/// Checks that the innermost binding of a name is used, both when typechecking and when removing
+/// names.
+#[test]
+fn shadowed_lambda() {
+    const SRC: &str = "λx:int. λx:int -> int. x 1";
+
+    let expr = SRC.parse::<Expr>().unwrap();
+    assert_eq!(
+        expr.tyck().unwrap().to_string(),
+        "(int) -> ((int) -> int) -> int"
+    );
+    assert_eq!(
+        remove_names(&[], &mut Vec::new(), &expr).unwrap(),
+        NamelessExpr::Lam(Box::new(NamelessExpr::Lam(Box::new(NamelessExpr::App(
+            Box::new(NamelessExpr::Var(0)),
+            Box::new(NamelessExpr::Lit(1)),
+        )))))
+    );
+}
+
+/// Checks that a variable can shadow a builtin.
+#[test]
+fn shadowed_builtin() {
+    const SRC: &str = "(λ+:int. +) 5";
+
+    let expr = SRC.parse::<Expr>().unwrap();
+    assert_eq!(expr.tyck().unwrap().to_string(), "int");
+    assert_eq!(
+        remove_names(&[], &mut Vec::new(), &expr).unwrap(),
+        NamelessExpr::App(
+            Box::new(NamelessExpr::Lam(Box::new(NamelessExpr::Var(0)))),
+            Box::new(NamelessExpr::Lit(5)),
+        )
+    );
+
+    let mut machine = Machine::new(expr.to_instructions().unwrap(), Value::Num(0));
+    assert_eq!(machine.run().unwrap(), Value::Num(5));
+}
+
+/// Checks that errors from each stage carry structured information.
+#[test]
+fn structured_errors() {
+    match "(λx:int. x".parse::<Expr>() {
+        Err(Error::UnexpectedToken {
+            span,
+            token: None,
+            expected,
+        }) => {
+            assert_eq!(span, Span::new(11, 11));
+            assert!(expected.contains(&r#"")""#.to_string()));
+        }
+        r => panic!("expected a parse error, got {:?}", r),
+    }
+
+    match "(λx:int. x) (λx:int. x)".parse::<Expr>().unwrap().tyck() {
+        Err(Error::TypeMismatch {
+            expr,
+            span,
+            expected,
+            actual,
+        }) => {
+            assert_eq!(expr, "(λx:int. x)");
+            assert_eq!(span, Span::new(14, 24));
+            assert_eq!(*expected, Type::Int);
+            assert_eq!(actual.to_string(), "(int) -> int");
+        }
+        r => panic!("expected a type mismatch, got {:?}", r),
+    }
+
+    match "1 2".parse::<Expr>().unwrap().tyck() {
+        Err(Error::NotAFunction { expr, span, ty }) => {
+            assert_eq!(expr, "1");
+            assert_eq!(span, Span::new(0, 1));
+            assert_eq!(*ty, Type::Int);
+        }
+        r => panic!("expected a non-function error, got {:?}", r),
+    }
+
+    match "λx:int. y".parse::<Expr>().unwrap().to_combinators() {
+        Err(Error::UnboundVariable { name, span }) => {
+            assert_eq!(name, "y");
+            assert_eq!(span, Span::new(9, 10));
+        }
+        r => panic!("expected an unbound variable error, got {:?}", r),
+    }
+}
+
+/// Checks the rendering of diagnostics.
+#[test]
+fn render_diagnostic() {
+    const SRC: &str = "λf:int -> int.\n  f (f f)\n";
+
+    let err = SRC.parse::<Expr>().unwrap().tyck().unwrap_err();
+    assert_eq!(
+        err.render("test.stlc", SRC),
+        "error: Expected f to have type int, but it has type (int) -> int
+ --> test.stlc:2:8
+  |
+2 |   f (f f)
+  |        ^"
+    );
+}
+
+/// Compiles a `let`, checking that it binds directly rather than through a closure.
+#[test]
+fn let_binding() {
+    const SRC: &str = "let x = 1 in x";
+
+    let expr = SRC.parse::<Expr>().unwrap();
+    assert_eq!(expr.to_string(), "(let x = 1 in x)");
+    assert_eq!(expr.tyck().unwrap(), Arc::new(Type::Int));
+
+    let combinator = expr.to_combinators().unwrap();
+    assert_eq!(
+        combinator,
+        StaticCombinator::Com(
+            Box::new(StaticCombinator::Snd),
+            Box::new(StaticCombinator::Bind(Box::new(
+                StaticCombinator::QuoteNum(1)
+            ))),
+        )
+    );
+    assert_eq!(
+        combinator.to_forth("test"),
+        &[&[
+            ":",
+            "fstlc-test-main",
+            "0",
+            "DUP",
+            "DROP",
+            "1",
+            "FSTLC-MAKE-PAIR",
+            "FSTLC-SND",
+            ";"
+        ]]
+    );
+}
+
+/// Evaluates nested `let`s, with and without annotations.
+#[test]
+fn let_eval() {
+    const SRC: &str = "let x = 5 in let f : int -> int = + x in f 10";
+
+    let expr = SRC.parse::<Expr>().unwrap();
+    assert_eq!(
+        expr.to_string(),
+        "(let x = 5 in (let f:(int) -> int = (+ x) in (f 10)))"
+    );
+    assert_eq!(expr.tyck().unwrap(), Arc::new(Type::Int));
+
+    let combinator = DynamicCombinator::Apply(
+        expr.to_combinators().unwrap().into(),
+        Box::new(DynamicCombinator::Num(0)),
+    );
+    assert_eq!(combinator.eval(), DynamicCombinator::Num(15));
+
+    let mut machine = Machine::new(expr.to_instructions().unwrap(), Value::Num(0));
+    assert_eq!(machine.run().unwrap(), Value::Num(15));
+
+    match "let x : int -> int = 5 in x"
+        .parse::<Expr>()
+        .unwrap()
+        .tyck()
+    {
+        Err(Error::TypeMismatch { expr, .. }) => assert_eq!(expr, "5"),
+        r => panic!("expected a type mismatch, got {:?}", r),
+    }
+}
+
+/// Compiles a conditional, checking that only the chosen branch is evaluated.
+#[test]
+fn if_then_else() {
+    const SRC: &str = "λx:int. if < x 10 then (print x; x) else x";
+
+    let expr = SRC.parse::<Expr>().unwrap();
+    assert_eq!(expr.tyck().unwrap().to_string(), "(int) -> int");
+
+    let forth = "if true then 1 else 2"
+        .parse::<Expr>()
+        .unwrap()
+        .compile("test")
+        .unwrap();
+    assert_eq!(
+        forth,
+        &[&[
+            ":",
+            "fstlc-test-main",
+            "0",
+            "DUP",
+            "DROP",
+            "-1",
+            "FSTLC-MAKE-PAIR",
+            "FSTLC-BRANCH",
+            "IF",
+            "DROP",
+            "1",
+            "ELSE",
+            "DROP",
+            "2",
+            "THEN",
+            ";"
+        ]]
+    );
+
+    for (arg, result, output) in &[(5, 5, "5\n"), (15, 15, "")] {
+        let src = format!("({}) {}", SRC, arg);
+        let expr = src.parse::<Expr>().unwrap();
+
+        let combinator = DynamicCombinator::Apply(
+            expr.to_combinators().unwrap().into(),
+            Box::new(DynamicCombinator::Num(0)),
+        );
+        let mut out = Vec::new();
+        assert_eq!(
+            combinator.eval_with(&mut out),
+            DynamicCombinator::Num(*result)
+        );
+        assert_eq!(out, output.as_bytes());
+
+        let mut machine = Machine::new(expr.to_instructions().unwrap(), Value::Num(0));
+        let mut out = Vec::new();
+        assert_eq!(machine.run_with(&mut out).unwrap(), Value::Num(*result));
+        assert_eq!(out, output.as_bytes());
+    }
+
+    match "if 1 then true else false".parse::<Expr>().unwrap().tyck() {
+        Err(Error::TypeMismatch { expected, .. }) => assert_eq!(*expected, Type::Bool),
+        r => panic!("expected a type mismatch, got {:?}", r),
+    }
+    match "if true then 1 else false".parse::<Expr>().unwrap().tyck() {
+        Err(Error::TypeMismatch { expr, .. }) => assert_eq!(expr, "false"),
+        r => panic!("expected a type mismatch, got {:?}", r),
+    }
+}
+
+/// Evaluates a recursive factorial function.
+#[test]
+fn let_rec_fact() {
+    const SRC: &str =
+        "let rec fact : int -> int = λn:int. if = n 0 then 1 else * n (fact (- n 1)) \
+                       in fact 5";
+
+    let expr = SRC.parse::<Expr>().unwrap();
+    assert_eq!(expr.tyck().unwrap(), Arc::new(Type::Int));
+
+    let combinator = DynamicCombinator::Apply(
+        expr.to_combinators().unwrap().into(),
+        Box::new(DynamicCombinator::Num(0)),
+    );
+    assert_eq!(combinator.eval(), DynamicCombinator::Num(120));
+
+    let mut machine = Machine::new(expr.to_instructions().unwrap(), Value::Num(0));
+    assert_eq!(machine.run().unwrap(), Value::Num(120));
+
+    match "let rec x : int = 1 in x".parse::<Expr>().unwrap().tyck() {
+        Err(Error::InvalidLetRec { expr, .. }) => assert_eq!(expr, "1"),
+        r => panic!("expected an invalid let rec, got {:?}", r),
+    }
+}
+
+/// Compiles a recursive function to Forth.
+#[test]
+fn let_rec_forth() {
+    const SRC: &str = "let rec f : int -> int = λx:int. f x in f";
+
+    let forth = SRC.parse::<Expr>().unwrap().compile("test").unwrap();
+    assert_eq!(
+        forth,
+        &[
+            &[
+                ":",
+                "fstlc-lambda-test-0",
+                "DUP",
+                "FSTLC-FST",
+                "FSTLC-SND",
+                "SWAP",
+                "FSTLC-SND",
+                "FSTLC-MAKE-PAIR",
+                "FSTLC-APP",
+                ";"
+            ] as &[_],
+            &[
+                ":",
+                "fstlc-test-main",
+                "0",
+                "'",
+                "fstlc-lambda-test-0",
+                "FSTLC-REC",
+                "FSTLC-SND",
+                ";"
+            ] as &[_],
+        ]
+    );
+}
+
+/// Builds and projects from a pair, which shares its representation with environments.
+#[test]
+fn pairs() {
+    const SRC: &str = "(λp:int * int. (snd p, fst p)) (1, * 2 3)";
+
+    let expr = SRC.parse::<Expr>().unwrap();
+    assert_eq!(
+        expr.to_string(),
+        "((λp:(int) * (int). ((snd p), (fst p))) (1, ((* 2) 3)))"
+    );
+    assert_eq!(
+        expr.tyck().unwrap(),
+        Arc::new(Type::Prod(Arc::new(Type::Int), Arc::new(Type::Int)))
+    );
+
+    let combinator = DynamicCombinator::Apply(
+        expr.to_combinators().unwrap().into(),
+        Box::new(DynamicCombinator::Num(0)),
+    );
+    assert_eq!(
+        combinator.eval(),
+        DynamicCombinator::DPair(
+            Box::new(DynamicCombinator::Num(6)),
+            Box::new(DynamicCombinator::Num(1))
+        )
+    );
+
+    let mut machine = Machine::new(expr.to_instructions().unwrap(), Value::Num(0));
+    assert_eq!(
+        machine.run().unwrap(),
+        Value::Pair(Rc::new(Value::Num(6)), Rc::new(Value::Num(1)))
+    );
+
+    let forth = "fst (1, 2)"
+        .parse::<Expr>()
+        .unwrap()
+        .compile("test")
+        .unwrap();
+    assert_eq!(
+        forth,
+        &[&[
+            ":",
+            "fstlc-test-main",
+            "0",
+            "DUP",
+            "DROP",
+            "1",
+            "SWAP",
+            "DROP",
+            "2",
+            "FSTLC-MAKE-PAIR",
+            "FSTLC-FST",
+            ";"
+        ] as &[_]]
+    );
+
+    match "fst 1".parse::<Expr>().unwrap().tyck() {
+        Err(Error::NotAPair { expr, span, ty }) => {
+            assert_eq!(expr, "1");
+            assert_eq!(span, Span::new(4, 5));
+            assert_eq!(*ty, Type::Int);
+        }
+        r => panic!("expected a non-pair, got {:?}", r),
+    }
+}
+
+/// Uses a sum type to return an error from a function.
+#[test]
+fn sums() {
+    const SRC: &str = "let div = λn:int. λd:int. \
+                       if = d 0 then inr[int + bool] true else inl[int + bool] (/ n d) in \
+                       (case div 84 2 of inl q => q | inr e => 1000, \
+                        case div 84 0 of inl q => q | inr e => if e then 1000 else 0)";
+
+    let expr = SRC.parse::<Expr>().unwrap();
+    assert_eq!(
+        expr.tyck().unwrap(),
+        Arc::new(Type::Prod(Arc::new(Type::Int), Arc::new(Type::Int)))
+    );
+
+    let combinator = DynamicCombinator::Apply(
+        expr.to_combinators().unwrap().into(),
+        Box::new(DynamicCombinator::Num(0)),
+    );
+    assert_eq!(
+        combinator.eval(),
+        DynamicCombinator::DPair(
+            Box::new(DynamicCombinator::Num(42)),
+            Box::new(DynamicCombinator::Num(1000))
+        )
+    );
+
+    let mut machine = Machine::new(expr.to_instructions().unwrap(), Value::Num(0));
+    assert_eq!(
+        machine.run().unwrap(),
+        Value::Pair(Rc::new(Value::Num(42)), Rc::new(Value::Num(1000)))
+    );
+
+    let expr = "inr[bool + int] 5".parse::<Expr>().unwrap();
+    assert_eq!(expr.tyck().unwrap().to_string(), "(bool) + (int)");
+    let mut machine = Machine::new(expr.to_instructions().unwrap(), Value::Num(0));
+    assert_eq!(machine.run().unwrap(), Value::Inr(Rc::new(Value::Num(5))));
+
+    let forth = "case inl[int + int] 1 of inl x => x | inr y => 2"
+        .parse::<Expr>()
+        .unwrap()
+        .compile("test")
+        .unwrap();
+    assert_eq!(
+        forth,
+        &[&[
+            ":",
+            "fstlc-test-main",
+            "0",
+            "DUP",
+            "DROP",
+            "1",
+            "FSTLC-INL",
+            "FSTLC-MAKE-PAIR",
+            "FSTLC-CASE",
+            "IF",
+            "FSTLC-SND",
+            "ELSE",
+            "DROP",
+            "2",
+            "THEN",
+            ";"
+        ] as &[_]]
+    );
+
+    match "case 1 of inl x => x | inr y => y"
+        .parse::<Expr>()
+        .unwrap()
+        .tyck()
+    {
+        Err(Error::NotASum { expr, ty, .. }) => {
+            assert_eq!(expr, "1");
+            assert_eq!(*ty, Type::Int);
+        }
+        r => panic!("expected a non-sum, got {:?}", r),
+    }
+    match "inl[int] 1".parse::<Expr>().unwrap().tyck() {
+        Err(Error::NotASum { expr, ty, .. }) => {
+            assert_eq!(expr, "(inl[int] 1)");
+            assert_eq!(*ty, Type::Int);
+        }
+        r => panic!("expected a non-sum, got {:?}", r),
+    }
+}
+
+/// Sequences effectful expressions, checking that the unit values are dropped.
+#[test]
+fn unit_sequencing() {
+    const SRC: &str = "let f = λx:int. print x; print (* x 2) in f 1; f 2; ()";
+
+    let expr = SRC.parse::<Expr>().unwrap();
+    assert_eq!(
+        expr.to_string(),
+        "(let f = (λx:int. ((print x); (print ((* x) 2)))) in ((f 1); ((f 2); ())))"
+    );
+    assert_eq!(expr.tyck().unwrap(), Arc::new(Type::Unit));
+
+    let combinator = DynamicCombinator::Apply(
+        expr.to_combinators().unwrap().into(),
+        Box::new(DynamicCombinator::Num(0)),
+    );
+    let mut out = Vec::new();
+    assert_eq!(combinator.eval_with(&mut out), DynamicCombinator::Unit);
+    assert_eq!(out, b"1\n2\n2\n4\n");
+
+    let mut machine = Machine::new(expr.to_instructions().unwrap(), Value::Num(0));
+    let mut out = Vec::new();
+    assert_eq!(machine.run_with(&mut out).unwrap(), Value::Unit);
+    assert_eq!(out, b"1\n2\n2\n4\n");
+
+    let forth = "print 1; ()"
+        .parse::<Expr>()
+        .unwrap()
+        .compile("test")
+        .unwrap();
+    assert_eq!(
+        forth,
+        &[&[
+            ":",
+            "fstlc-test-main",
+            "0",
+            "DUP",
+            "DUP",
+            "DROP",
+            "FSTLC-BUILTIN-PRINT",
+            "SWAP",
+            "DROP",
+            "1",
+            "FSTLC-MAKE-PAIR",
+            "FSTLC-APP",
+            "DROP",
+            "DROP",
+            "0",
+            ";"
+        ] as &[_]]
+    );
+
+    match "1; 2".parse::<Expr>().unwrap().tyck() {
+        Err(Error::TypeMismatch {
+            expr,
+            expected,
+            actual,
+            ..
+        }) => {
+            assert_eq!(expr, "1");
+            assert_eq!(*expected, Type::Unit);
+            assert_eq!(*actual, Type::Int);
+        }
+        r => panic!("expected a type mismatch, got {:?}", r),
+    }
+}
+
+/// Infers principal types for unannotated programs.
+#[test]
+fn type_inference() {
+    for (src, ty) in &[
+        ("λx. x", "('a) -> 'a"),
+        (
+            "λf. λg. λx. f (g x)",
+            "(('a) -> 'b) -> (('c) -> 'a) -> ('c) -> 'b",
+        ),
+        ("λp. (snd p, fst p)", "(('a) * ('b)) -> ('b) * ('a)"),
+        ("let id = λx. x in (id 1, id true)", "(int) * (bool)"),
+        (
+            "λx. case x of inl a => + a 1 | inr b => if b then 1 else 0",
+            "((int) + (bool)) -> int",
+        ),
+        ("inl 1", "(int) + ('a)"),
+    ] {
+        let expr = src.parse::<Expr>().unwrap();
+        assert_eq!(expr.tyck().unwrap().to_string(), *ty, "for {}", src);
+    }
+
+    const SRC: &str = "let rec fact = λn. if = n 0 then 1 else * n (fact (- n 1)) in fact 5";
+    let expr = SRC.parse::<Expr>().unwrap();
+    assert_eq!(expr.tyck().unwrap(), Arc::new(Type::Int));
+    let mut machine = Machine::new(expr.to_instructions().unwrap(), Value::Num(0));
+    assert_eq!(machine.run().unwrap(), Value::Num(120));
+
+    // Lambda-bound variables are not generalized.
+    match "λf. (f 1, f true)".parse::<Expr>().unwrap().tyck() {
+        Err(Error::TypeMismatch {
+            expr,
+            expected,
+            actual,
+            ..
+        }) => {
+            assert_eq!(expr, "true");
+            assert_eq!(*expected, Type::Int);
+            assert_eq!(*actual, Type::Bool);
+        }
+        r => panic!("expected a type mismatch, got {:?}", r),
+    }
+
+    match "λx. x x".parse::<Expr>().unwrap().tyck() {
+        Err(err @ Error::InfiniteType { .. }) => {
+            assert_eq!(err.span(), Some(Span::new(7, 8)));
+            assert_eq!(
+                err.to_string(),
+                "Cannot construct the infinite type 'b = ('b) -> 'c for x"
+            );
+        }
+        r => panic!("expected an infinite type, got {:?}", r),
+    }
+}
+
+/// Checks explicitly polymorphic programs, whose types are erased before compilation.
+#[test]
+fn system_f() {
+    for (src, ty) in &[
+        ("Λa. λx:a. x", "forall a. (a) -> a"),
+        (
+            "/\\a. /\\b. /\\c. \\f:b -> c. \\g:a -> b. \\x:a. f (g x)",
+            "forall a. forall b. forall c. ((b) -> c) -> ((a) -> b) -> (a) -> c",
+        ),
+        (
+            "let id = Λa. λx:a. x in (id [int] 1, id [bool] true)",
+            "(int) * (bool)",
+        ),
+        (
+            "λf:forall a. a -> a. (f [int] 1, f [bool] true)",
+            "(forall a. (a) -> a) -> (int) * (bool)",
+        ),
+    ] {
+        let expr = src.parse::<Expr>().unwrap();
+        assert_eq!(expr.tyck().unwrap().to_string(), *ty, "for {}", src);
+    }
+
+    const SRC: &str = "(λf:forall a. a -> a. (f [int] 1, f [bool] true)) (Λb. λx:b. x)";
+    let expr = SRC.parse::<Expr>().unwrap();
+    assert_eq!(expr.tyck().unwrap().to_string(), "(int) * (bool)");
+    assert_eq!(
+        expr.to_combinators().unwrap(),
+        "(λf. (f 1, f true)) (λx. x)"
+            .parse::<Expr>()
+            .unwrap()
+            .to_combinators()
+            .unwrap()
+    );
+    let mut machine = Machine::new(expr.to_instructions().unwrap(), Value::Num(0));
+    assert_eq!(
+        machine.run().unwrap(),
+        Value::Pair(Rc::new(Value::Num(1)), Rc::new(Value::Bool(true)))
+    );
+
+    // Instantiating a forall does not capture the parameters of the type it is instantiated with.
+    const CAPTURE: &str = "(Λb. (Λa. Λb. λx:a. λy:b. x) [b]) [int] [bool] 5 true";
+    let expr = CAPTURE.parse::<Expr>().unwrap();
+    assert_eq!(*expr.tyck().unwrap(), Type::Int);
+    let mut machine = Machine::new(expr.to_instructions().unwrap(), Value::Num(0));
+    assert_eq!(machine.run().unwrap(), Value::Num(5));
+    assert_eq!(
+        "(Λb. (Λa. Λb. λx:a. λy:b. x) [b])"
+            .parse::<Expr>()
+            .unwrap()
+            .tyck()
+            .unwrap()
+            .to_string(),
+        "forall b. forall b'. (b) -> (b') -> b"
+    );
+
+    match "1 [int]".parse::<Expr>().unwrap().tyck() {
+        Err(Error::NotPolymorphic { expr, ty, .. }) => {
+            assert_eq!(expr, "1");
+            assert_eq!(*ty, Type::Int);
+        }
+        r => panic!("expected a non-polymorphic expression, got {:?}", r),
+    }
+    match "λx:a. x".parse::<Expr>().unwrap().tyck() {
+        Err(Error::UnboundVariable { name, .. }) => assert_eq!(name, "a"),
+        r => panic!("expected an unbound type variable, got {:?}", r),
+    }
+    match "λx. Λa. (λy:a. y) x".parse::<Expr>().unwrap().tyck() {
+        Err(Error::EscapingTypeVariable { name, span }) => {
+            assert_eq!(name, "a");
+            assert_eq!(span, Span::new(5, 22));
+        }
+        r => panic!("expected an escaping type variable, got {:?}", r),
+    }
+}
+
+/// Compiles a program with several top-level definitions, each to its own word or variable.
+#[test]
+fn program_defs() {
+    const SRC: &str = "def one = 1\ndef main = + one one";
+
+    let program = SRC.parse::<Program>().unwrap();
+    assert_eq!(program.to_string(), "def one = 1\ndef main = ((+ one) one)");
+    assert_eq!(program.tyck().unwrap(), Arc::new(Type::Int));
+    assert_eq!(
+        program.compile("test").unwrap(),
+        &[
+            &[":", "fstlc-init-test-one", "0", "DROP", "1", ";"] as &[_],
+            &["fstlc-init-test-one", "FSTLC-DEF", "fstlc-test-one"] as &[_],
+            &[
+                ":",
+                "fstlc-test-main",
+                "0",
+                "DUP",
+                "DUP",
+                "DROP",
+                "FSTLC-BUILTIN-+",
+                "SWAP",
+                "DROP",
+                "fstlc-test-one",
+                "@",
+                "FSTLC-MAKE-PAIR",
+                "FSTLC-APP",
+                "SWAP",
+                "DROP",
+                "fstlc-test-one",
+                "@",
+                "FSTLC-MAKE-PAIR",
+                "FSTLC-APP",
+                ";"
+            ] as &[_],
+        ]
+    );
+
+    // Functions may refer to themselves, and are generalized for the definitions after them.
+    const FACT: &str = "def id = λx. x\n\
+                        def fact = λn. if = n 0 then 1 else * n (fact (- n 1))\n\
+                        def main = if id true then id fact 5 else 0";
+    let program = FACT.parse::<Program>().unwrap();
+    assert_eq!(program.tyck().unwrap(), Arc::new(Type::Int));
+    let expr = program.to_expr().unwrap();
+    let mut machine = Machine::new(expr.to_instructions().unwrap(), Value::Num(0));
+    assert_eq!(machine.run().unwrap(), Value::Num(120));
+    let forth = program.compile("test").unwrap();
+    let words = forth
+        .iter()
+        .map(|decl| decl[1].as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        words,
+        &[
+            "fstlc-lambda-test-0",
+            "fstlc-init-test-id",
+            "FSTLC-DEF",
+            "fstlc-lambda-test-1",
+            "fstlc-init-test-fact",
+            "FSTLC-DEF",
+            "fstlc-test-main"
+        ]
+    );
+
+    // A bare expression is a program that only defines main.
+    let program = "(λx. x) 1".parse::<Program>().unwrap();
+    assert_eq!(program.to_string(), "def main = ((λx. x) 1)");
+
+    match "def one = 1".parse::<Program>().unwrap().tyck() {
+        Err(Error::MissingMain) => {}
+        r => panic!("expected a missing main, got {:?}", r),
+    }
+}
+
+/// Evaluates a definition with an effect once, no matter how many times it is referenced.
+#[test]
+fn def_effects() {
+    const SRC: &str = "def x = print 1\ndef main = x; x";
+
+    let program = SRC.parse::<Program>().unwrap();
+    let expr = program.to_expr().unwrap();
+    let mut machine = Machine::new(expr.to_instructions().unwrap(), Value::Num(0));
+    let mut out = Vec::new();
+    assert_eq!(machine.run_with(&mut out).unwrap(), Value::Unit);
+    assert_eq!(out, b"1\n");
+
+    // The definition is evaluated when the Forth code is loaded, and main only reads its value.
+    let forth = program.compile("t1").unwrap();
+    assert_eq!(
+        forth[1],
+        &["fstlc-init-t1-x", "FSTLC-DEF", "fstlc-t1-x"] as &[_]
+    );
+    let main = forth.last().unwrap();
+    assert_eq!(main[1], "fstlc-t1-main");
+    let reads = main.windows(2).filter(|w| w == &["fstlc-t1-x", "@"]);
+    assert_eq!(reads.count(), 2);
+    assert!(main.iter().all(|word| word != "fstlc-init-t1-x"));
+}
+
+/// Loads a module that imports others, and checks that cyclic imports are rejected.
+#[test]
+fn imports() {
+    let examples = Path::new(env!("CARGO_MANIFEST_DIR")).join("examples");
+    let mut loader = Loader::new();
+    let root = loader.load(&examples.join("imports.stlc"), None).unwrap();
+    let prefixes = loader
+        .modules()
+        .iter()
+        .map(|module| module.prefix.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(prefixes, &["arith", "defs", "imports"]);
+    assert_eq!(loader.modules()[root].imports, &[0, 1]);
+
+    // Imported definitions are referenced by the words of their own modules.
+    let forth = loader.compile(root, &Default::default()).unwrap();
+    let main = forth.last().unwrap();
+    assert_eq!(main[1], "fstlc-imports-main");
+    assert!(main.iter().any(|word| word == "fstlc-arith-square"));
+    assert!(main.iter().any(|word| word == "fstlc-defs-double"));
+
+    // Loading a module again reuses it.
+    assert_eq!(loader.load(&examples.join("defs.stlc"), None).unwrap(), 1);
+
+    let expr = loader.to_program(root).to_expr().unwrap();
+    let mut machine = Machine::new(expr.to_instructions().unwrap(), Value::Num(0));
+    let mut out = Vec::new();
+    assert_eq!(machine.run_with(&mut out).unwrap(), Value::Unit);
+    assert_eq!(out, b"16\n");
+
+    let dir = std::env::temp_dir().join(format!("fstlc-imports-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+
+    // Names resolve to the modules the importer imports, even when a module it does not import
+    // defines the same name later.
+    fs::write(
+        dir.join("root.stlc"),
+        "import \"c.stlc\"\nimport \"b.stlc\"\ndef main = h",
+    )
+    .unwrap();
+    fs::write(dir.join("b.stlc"), "import \"d.stlc\"\ndef g = h").unwrap();
+    fs::write(dir.join("c.stlc"), "def h = 3").unwrap();
+    fs::write(dir.join("d.stlc"), "def h = 4").unwrap();
+    let mut loader = Loader::new();
+    let root = loader.load(&dir.join("root.stlc"), None).unwrap();
+    let forth = loader.compile(root, &Default::default()).unwrap();
+    assert!(forth.last().unwrap().iter().any(|word| word == "fstlc-c-h"));
+    let expr = loader.to_program(root).to_expr().unwrap();
+    let mut machine = Machine::new(expr.to_instructions().unwrap(), Value::Num(0));
+    assert_eq!(machine.run().unwrap(), Value::Num(3));
+    let program = loader.to_program(root).to_string();
+    assert_eq!(
+        program,
+        "def c.h = 3\ndef d.h = 4\ndef b.g = d.h\ndef main = c.h"
+    );
+
+    fs::write(dir.join("a.stlc"), "import \"b.stlc\"\ndef main = x").unwrap();
+    fs::write(dir.join("b.stlc"), "import \"a.stlc\"\ndef x = 1").unwrap();
+    let err = Loader::new().load(&dir.join("a.stlc"), None).unwrap_err();
+    fs::remove_dir_all(&dir).unwrap();
+    match err {
+        Error::InModule { path, err, .. } => {
+            assert!(path.ends_with("b.stlc"));
+            match *err {
+                Error::ImportCycle { path, span } => {
+                    assert_eq!(path, "a.stlc");
+                    assert_eq!(span, Span::new(0, 15));
+                }
+                err => panic!("Expected a cyclic import, got {}", err),
+            }
+        }
+        err => panic!("Expected an error in a module, got {}", err),
+    }
+}
+
+/// Parses a program with comments, which are attached to the items after them.
+#[test]
+fn comments() {
+    const SRC: &str = "-- Doubles a number.\n\
+                       def double = λx. + x x // inline\n\
+                       /* The entry point. /* Nested. */ */\n\
+                       def main = double 21--trailing";
+
+    let program = SRC.parse::<Program>().unwrap();
+    assert_eq!(
+        program.to_string(),
+        "def double = (λx. ((+ x) x))\ndef main = (double 21)"
+    );
+    let comments = program
+        .defs
+        .iter()
+        .map(|def| {
+            def.comments
+                .iter()
+                .map(|comment| comment.text.as_str())
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        comments,
+        &[
+            &["-- Doubles a number.", "// inline"] as &[_],
+            &["/* The entry point. /* Nested. */ */", "--trailing"] as &[_],
+        ]
+    );
+    assert_eq!(program.defs[1].comments[0].span, Span::new(55, 91));
+
+    match "1 /* /* */".parse::<Expr>() {
+        Err(Error::UnterminatedComment { span }) => assert_eq!(span, Span::new(2, 10)),
+        r => panic!("expected an unterminated comment, got {:?}", r),
+    }
+}
+
+/// Evaluates arithmetic on signed integers, which wraps on overflow and traps on division by zero,
+/// and checks that literals must fit in the target's cells.
+#[test]
+fn signed_integers() {
+    let cases: &[(&str, i64)] = &[
+        ("-5", -5),
+        ("+ -3 1", -2),
+        ("- 1 2", -1),
+        ("* 4611686018427387904 2", i64::MIN),
+        ("- -9223372036854775808 1", i64::MAX),
+        ("/ -7 2", -3),
+        ("mod -7 2", -1),
+    ];
+    for &(src, result) in cases {
+        let expr = src.parse::<Expr>().unwrap();
+        assert_eq!(expr.tyck().unwrap(), Arc::new(Type::Int));
+
+        let combinator = DynamicCombinator::Apply(
+            expr.to_combinators().unwrap().into(),
+            Box::new(DynamicCombinator::Num(0)),
+        );
+        assert_eq!(combinator.eval(), DynamicCombinator::Num(result));
+
+        let mut machine = Machine::new(expr.to_instructions().unwrap(), Value::Num(0));
+        assert_eq!(machine.run().unwrap(), Value::Num(result));
+    }
+
+    let expr = "/ 1 0".parse::<Expr>().unwrap();
+    let mut machine = Machine::new(expr.to_instructions().unwrap(), Value::Num(0));
+    match machine.run() {
+        Err(Error::DivisionByZero) => {}
+        r => panic!("expected division by zero, got {:?}", r),
+    }
+
+    match "9223372036854775808".parse::<Expr>() {
+        Err(Error::LiteralOutOfRange { span }) => assert_eq!(span, Span::new(0, 19)),
+        r => panic!("expected an out-of-range literal, got {:?}", r),
+    }
+
+    // Literals must also fit in a cell of the target.
+    let narrow = CompileOptions {
+        cell_width: 4,
+        ..CompileOptions::default()
+    };
+    let program = "def main = let x = 4294967296 in print x"
+        .parse::<Program>()
+        .unwrap();
+    assert!(program
+        .compile_with("test", &[], &CompileOptions::default())
+        .is_ok());
+    match program.compile_with("test", &[], &narrow) {
+        Err(Error::LiteralOutOfRange { span }) => assert_eq!(span, Span::new(19, 29)),
+        r => panic!("expected an out-of-range literal, got {:?}", r),
+    }
+    let program = "def main = - -2147483648 2147483647"
+        .parse::<Program>()
+        .unwrap();
+    assert!(program.compile_with("test", &[], &narrow).is_ok());
+}
+
+/// Runs a combinator on both evaluators, checking that they print the same output. Returns the
+/// value from each evaluator, and the output.
+fn eval_both(c: StaticCombinator) -> (DynamicCombinator, Value, Vec<u8>) {
+    let mut machine = Machine::new(c.to_instructions(), Value::Num(0));
+    let mut machine_out = Vec::new();
+    let machine_value = machine.run_with(&mut machine_out).unwrap();
+
+    let combinator = DynamicCombinator::Apply(c.into(), Box::new(DynamicCombinator::Num(0)));
+    let mut out = Vec::new();
+    let value = combinator.eval_with(&mut out);
+    assert_eq!(out, machine_out);
+    (value, machine_value, out)
+}
+
+/// Checks that each optimization rule preserves the value and output of some programs.
+#[test]
+fn optimizer() {
+    const SRCS: &[&str] = &[
+        "(λx:int. λy:int. x) 0 1",
+        "(λf:int -> int. f (f 5)) (λx:int. * x 2)",
+        "let x = (print 1; 2) in fst (x, print x)",
+        "snd (print 1, 2)",
+        "let rec fact = λn. if = n 0 then 1 else * n (fact (- n 1)) in fact 5",
+        "case inl[int] (print 3; 4) of inl x => (λy. + x y) 1 | inr y => 0",
+    ];
+
+    let mut rule_sets = OptimizationRule::ALL
+        .iter()
+        .map(|&rule| vec![rule])
+        .collect::<Vec<_>>();
+    rule_sets.push(OptimizationRule::ALL.to_vec());
+    for src in SRCS {
+        let combinator = src.parse::<Expr>().unwrap().to_combinators().unwrap();
+        let expected = eval_both(combinator.clone());
+        for rules in &rule_sets {
+            let optimized = combinator.optimize(rules);
+            assert_eq!(eval_both(optimized), expected, "{} with {:?}", src, rules);
+        }
+    }
+
+    // References to definitions other than main only read a variable, so they may be discarded.
+    let def = |n: &str| StaticCombinator::QuoteDef("test".to_string(), n.to_string());
+    let pair = |l| StaticCombinator::Pair(Box::new(l), Box::new(StaticCombinator::QuoteNum(2)));
+    let snd = |l| StaticCombinator::Com(Box::new(StaticCombinator::Snd), Box::new(pair(l)));
+    assert_eq!(
+        snd(def("x")).optimize(&[OptimizationRule::SndPair]),
+        StaticCombinator::QuoteNum(2)
+    );
+    assert_eq!(
+        snd(def("main")).optimize(&[OptimizationRule::SndPair]),
+        snd(def("main"))
+    );
+
+    // Effects are never discarded.
+    let combinator = "snd (print 1, 2)"
+        .parse::<Expr>()
+        .unwrap()
+        .to_combinators()
+        .unwrap();
+    assert_eq!(
+        combinator.optimize(OptimizationRule::ALL).to_string(),
+        "Snd ∘ <App ∘ <'print, '1>, '2>"
+    );
+    let combinator = "snd (1, 2)"
+        .parse::<Expr>()
+        .unwrap()
+        .to_combinators()
+        .unwrap();
+    assert_eq!(combinator.optimize(OptimizationRule::ALL).to_string(), "'2");
+
+    let combinator = "(λx:int. x) 5"
+        .parse::<Expr>()
+        .unwrap()
+        .to_combinators()
+        .unwrap();
+    assert_eq!(
+        combinator.optimize(&[OptimizationRule::Beta]).to_string(),
+        "Snd ∘ Bind('5)"
+    );
+    assert_eq!(combinator.optimize(&[]), combinator);
+}
+
+/// Builds chains of compositions, which are flattened and compiled without nesting.
+#[test]
+fn composition_chains() {
+    const SRC: &str = "(λp. fst (snd (snd p))) (1, (2, (3, 4)))";
+
+    let expr = SRC.parse::<Expr>().unwrap();
+    let combinator = expr.to_combinators().unwrap();
+    assert!(combinator.to_string().contains("Λ(Fst ∘ Snd ∘ Snd ∘ Snd)"));
+    let lambdas = combinator.to_forth("test");
+    assert_eq!(
+        lambdas[0],
+        &[
+            ":",
+            "fstlc-lambda-test-0",
+            "FSTLC-SND",
+            "FSTLC-SND",
+            "FSTLC-SND",
+            "FSTLC-FST",
+            ";"
+        ]
+    );
+
+    let mut machine = Machine::new(combinator.to_instructions(), Value::Num(0));
+    assert_eq!(machine.run().unwrap(), Value::Num(3));
+    let dynamic = DynamicCombinator::Apply(combinator.into(), Box::new(DynamicCombinator::Num(0)));
+    assert_eq!(dynamic.eval(), DynamicCombinator::Num(3));
+
+    let var = StaticCombinator::Seq(vec![
+        StaticCombinator::Snd,
+        StaticCombinator::Fst,
+        StaticCombinator::Fst,
+        StaticCombinator::Fst,
+    ]);
+    assert_eq!(var.to_string(), "Snd ∘ Fst ∘ Fst ∘ Fst");
+
+    // Nested chains are flattened, and identities are dropped.
+    let composed = StaticCombinator::compose(vec![
+        StaticCombinator::Com(
+            Box::new(StaticCombinator::Snd),
+            Box::new(StaticCombinator::Id),
+        ),
+        StaticCombinator::Com(
+            Box::new(StaticCombinator::Fst),
+            Box::new(StaticCombinator::Fst),
+        ),
+        var,
+    ]);
+    assert_eq!(
+        composed.to_string(),
+        "Snd ∘ Fst ∘ Fst ∘ Snd ∘ Fst ∘ Fst ∘ Fst"
+    );
+    assert_eq!(
+        StaticCombinator::compose(vec![StaticCombinator::Id, StaticCombinator::Snd]),
+        StaticCombinator::Snd
+    );
+    assert_eq!(StaticCombinator::compose(Vec::new()), StaticCombinator::Id);
+    assert_eq!(
+        StaticCombinator::Com(
+            Box::new(StaticCombinator::Id),
+            Box::new(StaticCombinator::Snd)
+        )
+        .optimize(&[OptimizationRule::Id]),
+        StaticCombinator::Snd
+    );
+}
+
+/// Accesses variables bound deep in the environment.
+#[test]
+fn environment_access() {
+    const SRC: &str = "(λw:int. λx:int. λy:int. λz:int. - w x) 10 2 3 4";
+
+    let expr = SRC.parse::<Expr>().unwrap();
+    let combinator = expr.to_combinators().unwrap();
+    assert!(combinator
+        .to_string()
+        .contains("Λ(Λ(Λ(Λ(App ∘ <App ∘ <'-, Access(3)>, Access(2)>))))"));
+    let forth = combinator.to_forth("test");
+    assert!(forth[0].windows(2).any(|w| w == ["3", "FSTLC-ACCESS"]));
+
+    let mut machine = Machine::new(combinator.to_instructions(), Value::Num(0));
+    assert_eq!(machine.run().unwrap(), Value::Num(8));
+    let dynamic = DynamicCombinator::Apply(combinator.into(), Box::new(DynamicCombinator::Num(0)));
+    assert_eq!(dynamic.eval(), DynamicCombinator::Num(8));
+
+    // Chains of projections are fused by the optimizer.
+    let chain = StaticCombinator::Seq(vec![
+        StaticCombinator::Snd,
+        StaticCombinator::Fst,
+        StaticCombinator::Fst,
+    ]);
+    assert_eq!(
+        chain.optimize(&[OptimizationRule::Access]),
+        StaticCombinator::Access(2)
+    );
+}
+
+/// Checks that flat closure conversion preserves the value and output of some programs, and that
+/// closures capture only the variables they use.
+#[test]
+fn flat_closures() {
+    const SRCS: &[&str] = &[
+        "(λx:int. λy:int. λz:int. x) 1 2 3",
+        "let k = 3 in let rec f = λn. if = n 0 then k else + k (f (- n 1)) in f 4",
+        "let a = 5 in let b = 6 in (λf:int -> int. f (f 1)) (λx:int. + a x)",
+    ];
+    let flat = CompileOptions {
+        flat_closures: true,
+        ..CompileOptions::default()
+    };
+
+    for src in SRCS {
+        let expr = src.parse::<Expr>().unwrap();
+        let expected = eval_both(expr.to_combinators().unwrap());
+        let converted = eval_both(expr.to_combinators_with(&flat).unwrap());
+        assert_eq!(converted, expected, "{}", src);
+    }
+
+    // Each closure captures only the variables it uses.
+    let expr = "λx:int. λy:int. λz:int. x".parse::<Expr>().unwrap();
+    let nameless = remove_names(&[], &mut Vec::new(), &expr).unwrap();
+    assert_eq!(nameless.closure_convert().to_string(), "λ[]λ[$0]λ[$1]$1");
+
+    let expr = "λk:int. let rec f = λn. f k in f".parse::<Expr>().unwrap();
+    let nameless = remove_names(&[], &mut Vec::new(), &expr).unwrap();
+    assert_eq!(
+        nameless.closure_convert().to_string(),
+        "λ[](let μλ[$0]($1 $2) in $0)"
+    );
+}
+
+/// Counts the pairs the CAM allocates, which the Forth runtime allocates too.
+#[test]
+fn allocation_counts() {
+    const SRCS: &[(&str, usize)] = &[
+        ("42", 0),
+        ("print 42", 3),
+        ("(λx:int. x) 1", 3),
+        ("+ 1 2", 6),
+        ("case inl[int] 1 of inl x => x | inr y => y", 3),
+        ("let rec f = λn. n in f 1", 4),
+    ];
+
+    for &(src, expected) in SRCS {
+        let combinator = src.parse::<Expr>().unwrap().to_combinators().unwrap();
+        let mut machine = Machine::new(combinator.to_instructions(), Value::Num(0));
+        machine.run_with(&mut Vec::new()).unwrap();
+        assert_eq!(machine.allocations(), expected, "{}", src);
+    }
+
+    // The Forth code allocates 4 pairs: 2 for the closure of id, and 2 to apply it in main. Binding
+    // one and main in the environment takes 2 more.
+    let program = "def one = 1\ndef id = λx. x\ndef main = id one"
+        .parse::<Program>()
+        .unwrap();
+    let expr = program.to_expr().unwrap();
+    let mut machine = Machine::new(expr.to_instructions().unwrap(), Value::Num(0));
+    machine.run().unwrap();
+    assert_eq!(machine.allocations(), 4 + 2);
 }
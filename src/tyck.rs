@@ -1,61 +1,634 @@
-use crate::{Expr, Type};
+//! Hindley-Milner type inference, extended with System F-style explicit polymorphism.
+//!
+//! Type variables (`Type::Var`) are solved by unification, while type parameters (`Type::Param`)
+//! are bound by type abstractions and only equal to themselves.
+
+use crate::{Error, Expr, Program, Type};
 use std::sync::Arc;
 
 lazy_static::lazy_static! {
-    static ref BUILTINS: Vec<(&'static str, &'static Arc<Type>)> = vec![
+    pub(crate) static ref BUILTINS: Vec<(&'static str, &'static Arc<Type>)> = vec![
         ("+", &INT_TO_INT_TO_INT),
         ("-", &INT_TO_INT_TO_INT),
         ("*", &INT_TO_INT_TO_INT),
         ("/", &INT_TO_INT_TO_INT),
         ("mod", &INT_TO_INT_TO_INT),
-        ("print", &INT_TO_INT),
+        ("print", &INT_TO_UNIT),
+        ("=", &INT_TO_INT_TO_BOOL),
+        ("<>", &INT_TO_INT_TO_BOOL),
+        ("<", &INT_TO_INT_TO_BOOL),
+        ("<=", &INT_TO_INT_TO_BOOL),
+        (">", &INT_TO_INT_TO_BOOL),
+        (">=", &INT_TO_INT_TO_BOOL),
     ];
+    static ref BOOL: Arc<Type> = Arc::new(Type::Bool);
     static ref INT: Arc<Type> = Arc::new(Type::Int);
+    static ref UNIT: Arc<Type> = Arc::new(Type::Unit);
     static ref INT_TO_INT: Arc<Type> = Arc::new(Type::Arr(INT.clone(), INT.clone()));
+    static ref INT_TO_BOOL: Arc<Type> = Arc::new(Type::Arr(INT.clone(), BOOL.clone()));
+    static ref INT_TO_UNIT: Arc<Type> = Arc::new(Type::Arr(INT.clone(), UNIT.clone()));
+    static ref INT_TO_INT_TO_BOOL: Arc<Type> = Arc::new(Type::Arr(INT.clone(), INT_TO_BOOL.clone()));
     static ref INT_TO_INT_TO_INT: Arc<Type> = Arc::new(Type::Arr(INT.clone(), INT_TO_INT.clone()));
 }
 
 impl Expr {
-    /// Typechecks the expression.
-    pub fn tyck(&self) -> Result<Arc<Type>, String> {
-        let mut scope = BUILTINS.clone();
-        tyck(&mut scope, self)
-    }
-}
-
-fn tyck<'e>(
-    scope: &mut Vec<(&'e str, &'e Arc<Type>)>,
-    expr: &'e Expr,
-) -> Result<Arc<Type>, String> {
-    match expr {
-        Expr::App(l, r) => {
-            let lt = tyck(scope, l)?;
-            let rt = tyck(scope, r)?;
-            match &*lt {
-                Type::Arr(llt, lrt) => {
-                    if llt == &rt {
-                        Ok(lrt.clone())
-                    } else {
-                        Err(format!(
-                            "{} expects an {}, but got {} (of type {})",
-                            l, llt, r, rt
-                        ))
-                    }
+    /// Infers the principal type of the expression. Type variables in the result are numbered in
+    /// the order they first appear.
+    pub fn tyck(&self) -> Result<Arc<Type>, Error> {
+        let mut infer = Infer::default();
+        let ty = infer.infer(&mut builtin_scope(), self)?;
+        Ok(normalize(&infer.zonk(&ty)))
+    }
+}
+
+impl Program {
+    /// Infers the type of the program, i.e. the type of `main`.
+    pub fn tyck(&self) -> Result<Arc<Type>, Error> {
+        self.tyck_defs(&[])?
+            .into_iter()
+            .rev()
+            .find(|(name, _)| name == "main")
+            .map(|(_, ty)| ty)
+            .ok_or(Error::MissingMain)
+    }
+
+    /// Infers the types of each of the program's definitions, given the types of the definitions
+    /// it imports. Type variables in the types are quantified over.
+    pub fn tyck_defs(
+        &self,
+        imports: &[(String, Arc<Type>)],
+    ) -> Result<Vec<(String, Arc<Type>)>, Error> {
+        let mut infer = Infer::default();
+        let mut scope = builtin_scope();
+        for (name, ty) in imports {
+            let scheme = infer.import(ty);
+            scope.push((name, scheme));
+        }
+
+        let mut types = Vec::new();
+        for def in &self.defs {
+            let recursive = matches!(def.body, Expr::Lam(..));
+            let scheme = infer.infer_binding(
+                &mut scope, &def.body, &def.name, &def.ty, &def.body, recursive,
+            )?;
+            types.push((def.name.clone(), normalize(&scheme.ty)));
+            scope.push((&def.name, scheme));
+        }
+        Ok(types)
+    }
+}
+
+/// Returns a scope containing only the builtins.
+fn builtin_scope() -> Vec<(&'static str, Scheme)> {
+    BUILTINS
+        .iter()
+        .map(|&(name, ty)| (name, Scheme::mono(ty.clone())))
+        .collect()
+}
+
+/// Renumbers the type variables in a type in the order they first appear.
+fn normalize(ty: &Arc<Type>) -> Arc<Type> {
+    let mut vars = Vec::new();
+    free_vars(ty, &mut vars);
+    let renaming = vars
+        .into_iter()
+        .enumerate()
+        .map(|(i, n)| (n, Arc::new(Type::Var(i))))
+        .collect::<Vec<_>>();
+    substitute(ty, &renaming)
+}
+
+/// A type scheme, i.e. a type that is universally quantified over some of its type variables.
+#[derive(Clone, Debug)]
+struct Scheme {
+    vars: Vec<usize>,
+    ty: Arc<Type>,
+}
+
+impl Scheme {
+    /// Creates a scheme that quantifies over no variables.
+    fn mono(ty: Arc<Type>) -> Scheme {
+        Scheme {
+            vars: Vec::new(),
+            ty,
+        }
+    }
+}
+
+/// The reason two types failed to unify.
+enum UnifyError {
+    /// The types have different shapes.
+    Mismatch,
+
+    /// The type variable would have to contain itself.
+    Occurs(usize, Arc<Type>),
+}
+
+/// The state of inference, i.e. the solutions found so far for each type variable, and the type
+/// parameters in scope.
+#[derive(Default)]
+struct Infer {
+    subst: Vec<Option<Arc<Type>>>,
+    params: Vec<String>,
+}
+
+impl Infer {
+    fn infer<'e>(
+        &mut self,
+        scope: &mut Vec<(&'e str, Scheme)>,
+        expr: &'e Expr,
+    ) -> Result<Arc<Type>, Error> {
+        match expr {
+            Expr::App(l, r, _) => {
+                let (lt, rt) = self.infer_arr(scope, l)?;
+                self.expect(scope, r, &lt)?;
+                Ok(rt)
+            }
+            Expr::Bool(_, _) => Ok(BOOL.clone()),
+            Expr::Case(e, x, a, y, b, _) => {
+                let (lt, rt) = self.infer_sum(scope, e)?;
+                scope.push((x, Scheme::mono(lt)));
+                let at = self.infer(scope, a)?;
+                scope.pop();
+                scope.push((y, Scheme::mono(rt)));
+                self.expect(scope, b, &at)?;
+                scope.pop();
+                Ok(at)
+            }
+            Expr::Fst(e, _) => self.infer_pair(scope, e).map(|(l, _)| l),
+            Expr::If(c, a, b, _) => {
+                self.expect(scope, c, &BOOL)?;
+                let at = self.infer(scope, a)?;
+                self.expect(scope, b, &at)?;
+                Ok(at)
+            }
+            Expr::Inl(t, e, _) => {
+                self.check_params(expr, t)?;
+                let (lt, rt) = self.injection(expr, t)?;
+                self.expect(scope, e, &lt)?;
+                Ok(Arc::new(Type::Sum(lt, rt)))
+            }
+            Expr::Inr(t, e, _) => {
+                self.check_params(expr, t)?;
+                let (lt, rt) = self.injection(expr, t)?;
+                self.expect(scope, e, &rt)?;
+                Ok(Arc::new(Type::Sum(lt, rt)))
+            }
+            Expr::Lam(s, t, e, _) => {
+                self.check_params(expr, t)?;
+                let t = t.clone().unwrap_or_else(|| self.fresh());
+                scope.push((s, Scheme::mono(t.clone())));
+                let et = self.infer(scope, e)?;
+                scope.pop();
+                Ok(Arc::new(Type::Arr(t, et)))
+            }
+            Expr::Let(s, t, e1, e2, _) => {
+                let scheme = self.infer_binding(scope, expr, s, t, e1, false)?;
+                scope.push((s, scheme));
+                let t2 = self.infer(scope, e2)?;
+                scope.pop();
+                Ok(t2)
+            }
+            Expr::LetRec(s, t, e1, e2, _) => {
+                let scheme = self.infer_binding(scope, expr, s, t, e1, true)?;
+                scope.push((s, scheme));
+                let t2 = self.infer(scope, e2)?;
+                scope.pop();
+                Ok(t2)
+            }
+            Expr::Lit(_, _) => Ok(INT.clone()),
+            Expr::Seq(e1, e2, _) => {
+                self.expect(scope, e1, &UNIT)?;
+                self.infer(scope, e2)
+            }
+            Expr::Snd(e, _) => self.infer_pair(scope, e).map(|(_, r)| r),
+            Expr::Tuple(l, r, _) => {
+                let lt = self.infer(scope, l)?;
+                let rt = self.infer(scope, r)?;
+                Ok(Arc::new(Type::Prod(lt, rt)))
+            }
+            Expr::TyApp(e, t, _) => {
+                self.check_params(expr, &Some(t.clone()))?;
+                let ty = self.infer(scope, e)?;
+                match &*self.resolve(&ty) {
+                    Type::Forall(a, body) => Ok(substitute_param(body, a, t)),
+                    _ => Err(Error::NotPolymorphic {
+                        expr: e.to_string(),
+                        span: e.span(),
+                        ty: self.zonk(&ty),
+                    }),
                 }
-                _ => Err(format!("Not a function: {}", l)),
             }
+            Expr::TyLam(a, e, span) => {
+                let shadowing = self.params.contains(a);
+                self.params.push(a.clone());
+                let et = self.infer(scope, e);
+                self.params.pop();
+                let et = et?;
+
+                // Unification may have solved a type variable from outside the abstraction with
+                // the parameter, which would then be used out of scope.
+                let escapes = !shadowing
+                    && scope.iter().any(|(_, scheme)| {
+                        let ty = self.zonk(&scheme.ty);
+                        let mut params = Vec::new();
+                        free_params(&ty, &mut Vec::new(), &mut params);
+                        params.contains(&a.as_str())
+                    });
+                if escapes {
+                    Err(Error::EscapingTypeVariable {
+                        name: a.clone(),
+                        span: *span,
+                    })
+                } else {
+                    Ok(Arc::new(Type::Forall(a.clone(), et)))
+                }
+            }
+            Expr::Unit(_) => Ok(UNIT.clone()),
+            Expr::Var(s, span) => match scope.iter().rev().find(|(n, _)| n == s) {
+                Some((_, scheme)) => {
+                    let scheme = scheme.clone();
+                    Ok(self.instantiate(&scheme))
+                }
+                None => Err(Error::UnboundVariable {
+                    name: s.clone(),
+                    span: *span,
+                }),
+            },
         }
-        Expr::Lam(s, t, e) => {
-            scope.push((s, t));
-            let et = tyck(scope, e)?;
-            scope.pop();
-            Ok(Arc::new(Type::Arr(t.clone(), et)))
+    }
+
+    /// Infers the type of the right-hand side of a binding of `s`, generalizing it. Recursive
+    /// bindings must be of lambdas; the function is monomorphic in its own body. Annotation errors
+    /// are reported at `expr`.
+    fn infer_binding<'e>(
+        &mut self,
+        scope: &mut Vec<(&'e str, Scheme)>,
+        expr: &Expr,
+        s: &'e str,
+        t: &Option<Arc<Type>>,
+        e1: &'e Expr,
+        recursive: bool,
+    ) -> Result<Scheme, Error> {
+        self.check_params(expr, t)?;
+        let t1 = if recursive {
+            if let Expr::Lam(..) = *e1 {
+                let t = t.clone().unwrap_or_else(|| self.fresh());
+                scope.push((s, Scheme::mono(t.clone())));
+                let r = self.expect(scope, e1, &t);
+                scope.pop();
+                r?;
+                t
+            } else {
+                return Err(Error::InvalidLetRec {
+                    expr: e1.to_string(),
+                    span: e1.span(),
+                });
+            }
+        } else {
+            let t1 = self.infer(scope, e1)?;
+            if let Some(t) = t {
+                self.check(e1, t, &t1)?;
+            }
+            t1
+        };
+        Ok(self.generalize(scope, &t1))
+    }
+
+    /// Infers the type of an expression, which must be a function. Returns the types of its
+    /// argument and result.
+    fn infer_arr<'e>(
+        &mut self,
+        scope: &mut Vec<(&'e str, Scheme)>,
+        expr: &'e Expr,
+    ) -> Result<(Arc<Type>, Arc<Type>), Error> {
+        let ty = self.infer(scope, expr)?;
+        match &*self.resolve(&ty) {
+            Type::Arr(l, r) => Ok((l.clone(), r.clone())),
+            Type::Var(_) => {
+                let (l, r) = (self.fresh(), self.fresh());
+                self.check(expr, &Arc::new(Type::Arr(l.clone(), r.clone())), &ty)?;
+                Ok((l, r))
+            }
+            _ => Err(Error::NotAFunction {
+                expr: expr.to_string(),
+                span: expr.span(),
+                ty: self.zonk(&ty),
+            }),
+        }
+    }
+
+    /// Infers the type of an expression, which must be a pair. Returns the types of its
+    /// components.
+    fn infer_pair<'e>(
+        &mut self,
+        scope: &mut Vec<(&'e str, Scheme)>,
+        expr: &'e Expr,
+    ) -> Result<(Arc<Type>, Arc<Type>), Error> {
+        let ty = self.infer(scope, expr)?;
+        match &*self.resolve(&ty) {
+            Type::Prod(l, r) => Ok((l.clone(), r.clone())),
+            Type::Var(_) => {
+                let (l, r) = (self.fresh(), self.fresh());
+                self.check(expr, &Arc::new(Type::Prod(l.clone(), r.clone())), &ty)?;
+                Ok((l, r))
+            }
+            _ => Err(Error::NotAPair {
+                expr: expr.to_string(),
+                span: expr.span(),
+                ty: self.zonk(&ty),
+            }),
+        }
+    }
+
+    /// Infers the type of an expression, which must be a sum. Returns the types of its
+    /// alternatives.
+    fn infer_sum<'e>(
+        &mut self,
+        scope: &mut Vec<(&'e str, Scheme)>,
+        expr: &'e Expr,
+    ) -> Result<(Arc<Type>, Arc<Type>), Error> {
+        let ty = self.infer(scope, expr)?;
+        match &*self.resolve(&ty) {
+            Type::Sum(l, r) => Ok((l.clone(), r.clone())),
+            Type::Var(_) => {
+                let (l, r) = (self.fresh(), self.fresh());
+                self.check(expr, &Arc::new(Type::Sum(l.clone(), r.clone())), &ty)?;
+                Ok((l, r))
+            }
+            _ => Err(Error::NotASum {
+                expr: expr.to_string(),
+                span: expr.span(),
+                ty: self.zonk(&ty),
+            }),
+        }
+    }
+
+    /// Returns the types of the alternatives of the sum an injection is into. If the injection is
+    /// not annotated, these are fresh type variables.
+    fn injection(
+        &mut self,
+        expr: &Expr,
+        ty: &Option<Arc<Type>>,
+    ) -> Result<(Arc<Type>, Arc<Type>), Error> {
+        match ty {
+            Some(ty) => match &**ty {
+                Type::Sum(l, r) => Ok((l.clone(), r.clone())),
+                _ => Err(Error::NotASum {
+                    expr: expr.to_string(),
+                    span: expr.span(),
+                    ty: ty.clone(),
+                }),
+            },
+            None => Ok((self.fresh(), self.fresh())),
         }
-        Expr::Lit(_) => Ok(Arc::new(Type::Int)),
-        Expr::Var(s) => scope
+    }
+
+    /// Checks that the type parameters in an annotation are in scope.
+    fn check_params(&self, expr: &Expr, ty: &Option<Arc<Type>>) -> Result<(), Error> {
+        let mut params = Vec::new();
+        if let Some(ty) = ty {
+            free_params(ty, &mut Vec::new(), &mut params);
+        }
+        match params
+            .into_iter()
+            .find(|a| !self.params.iter().any(|b| a == b))
+        {
+            Some(a) => Err(Error::UnboundVariable {
+                name: a.to_string(),
+                span: expr.span(),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Infers the type of an expression, which must have the given type.
+    fn expect<'e>(
+        &mut self,
+        scope: &mut Vec<(&'e str, Scheme)>,
+        expr: &'e Expr,
+        expected: &Arc<Type>,
+    ) -> Result<(), Error> {
+        let actual = self.infer(scope, expr)?;
+        self.check(expr, expected, &actual)
+    }
+
+    /// Checks that the type of an expression can be made equal to the expected one.
+    fn check(
+        &mut self,
+        expr: &Expr,
+        expected: &Arc<Type>,
+        actual: &Arc<Type>,
+    ) -> Result<(), Error> {
+        match self.unify(expected, actual) {
+            Ok(()) => Ok(()),
+            Err(UnifyError::Mismatch) => Err(Error::TypeMismatch {
+                expr: expr.to_string(),
+                span: expr.span(),
+                expected: self.zonk(expected),
+                actual: self.zonk(actual),
+            }),
+            Err(UnifyError::Occurs(n, ty)) => Err(Error::InfiniteType {
+                expr: expr.to_string(),
+                span: expr.span(),
+                var: Arc::new(Type::Var(n)),
+                ty: self.zonk(&ty),
+            }),
+        }
+    }
+
+    /// Creates a new type variable.
+    fn fresh(&mut self) -> Arc<Type> {
+        self.subst.push(None);
+        Arc::new(Type::Var(self.subst.len() - 1))
+    }
+
+    /// Replaces solved type variables at the root of a type with their solutions.
+    fn resolve(&self, ty: &Arc<Type>) -> Arc<Type> {
+        let mut ty = ty.clone();
+        while let Type::Var(n) = *ty {
+            match self.subst[n] {
+                Some(ref solution) => ty = solution.clone(),
+                None => break,
+            }
+        }
+        ty
+    }
+
+    /// Replaces all solved type variables in a type with their solutions.
+    fn zonk(&self, ty: &Arc<Type>) -> Arc<Type> {
+        let ty = self.resolve(ty);
+        match &*ty {
+            Type::Arr(l, r) => Arc::new(Type::Arr(self.zonk(l), self.zonk(r))),
+            Type::Prod(l, r) => Arc::new(Type::Prod(self.zonk(l), self.zonk(r))),
+            Type::Sum(l, r) => Arc::new(Type::Sum(self.zonk(l), self.zonk(r))),
+            Type::Forall(a, t) => Arc::new(Type::Forall(a.clone(), self.zonk(t))),
+            Type::Bool | Type::Int | Type::Param(_) | Type::Unit | Type::Var(_) => ty,
+        }
+    }
+
+    /// Solves type variables so that the two types are equal.
+    fn unify(&mut self, a: &Arc<Type>, b: &Arc<Type>) -> Result<(), UnifyError> {
+        let (a, b) = (self.resolve(a), self.resolve(b));
+        match (&*a, &*b) {
+            (Type::Var(m), Type::Var(n)) if m == n => Ok(()),
+            (&Type::Var(n), _) => self.bind(n, &b),
+            (_, &Type::Var(n)) => self.bind(n, &a),
+            (Type::Arr(al, ar), Type::Arr(bl, br))
+            | (Type::Prod(al, ar), Type::Prod(bl, br))
+            | (Type::Sum(al, ar), Type::Sum(bl, br)) => {
+                self.unify(al, bl)?;
+                self.unify(ar, br)
+            }
+            (Type::Forall(a, at), Type::Forall(b, bt)) => {
+                // Foralls are equal up to the names of their parameters.
+                let bt = substitute_param(bt, b, &Arc::new(Type::Param(a.clone())));
+                self.unify(at, &bt)
+            }
+            (Type::Param(a), Type::Param(b)) if a == b => Ok(()),
+            (Type::Bool, Type::Bool) | (Type::Int, Type::Int) | (Type::Unit, Type::Unit) => Ok(()),
+            _ => Err(UnifyError::Mismatch),
+        }
+    }
+
+    /// Solves an unsolved type variable, performing the occurs check.
+    fn bind(&mut self, n: usize, ty: &Arc<Type>) -> Result<(), UnifyError> {
+        let mut vars = Vec::new();
+        free_vars(&self.zonk(ty), &mut vars);
+        if vars.contains(&n) {
+            Err(UnifyError::Occurs(n, ty.clone()))
+        } else {
+            self.subst[n] = Some(ty.clone());
+            Ok(())
+        }
+    }
+
+    /// Quantifies a type over the type variables in it that are not free in the scope.
+    fn generalize(&self, scope: &[(&str, Scheme)], ty: &Arc<Type>) -> Scheme {
+        let mut in_scope = Vec::new();
+        for (_, scheme) in scope {
+            let mut vars = Vec::new();
+            free_vars(&self.zonk(&scheme.ty), &mut vars);
+            in_scope.extend(vars.into_iter().filter(|n| !scheme.vars.contains(n)));
+        }
+
+        let ty = self.zonk(ty);
+        let mut vars = Vec::new();
+        free_vars(&ty, &mut vars);
+        vars.retain(|n| !in_scope.contains(n));
+        Scheme { vars, ty }
+    }
+
+    /// Quantifies a type from another module over all its type variables. These are replaced with
+    /// fresh ones, so they do not collide with the ones used in this module.
+    fn import(&mut self, ty: &Arc<Type>) -> Scheme {
+        let mut vars = Vec::new();
+        free_vars(ty, &mut vars);
+        let ty = self.instantiate(&Scheme {
+            vars,
+            ty: ty.clone(),
+        });
+        let mut vars = Vec::new();
+        free_vars(&ty, &mut vars);
+        Scheme { vars, ty }
+    }
+
+    /// Replaces the quantified variables of a scheme with fresh type variables.
+    fn instantiate(&mut self, scheme: &Scheme) -> Arc<Type> {
+        let fresh = scheme
+            .vars
+            .iter()
+            .map(|&n| (n, self.fresh()))
+            .collect::<Vec<_>>();
+        substitute(&scheme.ty, &fresh)
+    }
+}
+
+/// Collects the type variables in a type, in the order they first appear. The type should already
+/// have had its solved type variables replaced.
+fn free_vars(ty: &Type, vars: &mut Vec<usize>) {
+    match ty {
+        Type::Arr(l, r) | Type::Prod(l, r) | Type::Sum(l, r) => {
+            free_vars(l, vars);
+            free_vars(r, vars);
+        }
+        Type::Forall(_, t) => free_vars(t, vars),
+        Type::Bool | Type::Int | Type::Param(_) | Type::Unit => {}
+        Type::Var(n) => {
+            if !vars.contains(n) {
+                vars.push(*n);
+            }
+        }
+    }
+}
+
+/// Replaces type variables in a type according to the given mapping.
+fn substitute(ty: &Arc<Type>, mapping: &[(usize, Arc<Type>)]) -> Arc<Type> {
+    match &**ty {
+        Type::Arr(l, r) => Arc::new(Type::Arr(substitute(l, mapping), substitute(r, mapping))),
+        Type::Prod(l, r) => Arc::new(Type::Prod(substitute(l, mapping), substitute(r, mapping))),
+        Type::Sum(l, r) => Arc::new(Type::Sum(substitute(l, mapping), substitute(r, mapping))),
+        Type::Forall(a, t) => Arc::new(Type::Forall(a.clone(), substitute(t, mapping))),
+        Type::Bool | Type::Int | Type::Param(_) | Type::Unit => ty.clone(),
+        Type::Var(n) => mapping
             .iter()
-            .find(|(n, _)| n == s)
-            .map(|(_, ty)| (*ty).clone())
-            .ok_or_else(|| format!("Not found: {}", s)),
+            .find(|(m, _)| m == n)
+            .map(|(_, ty)| ty.clone())
+            .unwrap_or_else(|| ty.clone()),
+    }
+}
+
+/// Collects the type parameters in a type that are not bound by the given list or by a forall.
+fn free_params<'t>(ty: &'t Type, bound: &mut Vec<&'t str>, params: &mut Vec<&'t str>) {
+    match ty {
+        Type::Arr(l, r) | Type::Prod(l, r) | Type::Sum(l, r) => {
+            free_params(l, bound, params);
+            free_params(r, bound, params);
+        }
+        Type::Forall(a, t) => {
+            bound.push(a);
+            free_params(t, bound, params);
+            bound.pop();
+        }
+        Type::Param(a) => {
+            if !bound.contains(&a.as_str()) && !params.contains(&a.as_str()) {
+                params.push(a);
+            }
+        }
+        Type::Bool | Type::Int | Type::Unit | Type::Var(_) => {}
+    }
+}
+
+/// Replaces the type parameter with the given name by a type, e.g. to instantiate a forall. Foralls
+/// binding a parameter that is free in the replacement are renamed, so that it is not captured.
+fn substitute_param(ty: &Arc<Type>, name: &str, replacement: &Arc<Type>) -> Arc<Type> {
+    let go = |t| substitute_param(t, name, replacement);
+    match &**ty {
+        Type::Arr(l, r) => Arc::new(Type::Arr(go(l), go(r))),
+        Type::Prod(l, r) => Arc::new(Type::Prod(go(l), go(r))),
+        Type::Sum(l, r) => Arc::new(Type::Sum(go(l), go(r))),
+        Type::Forall(a, _) if a == name => ty.clone(),
+        Type::Forall(a, t) => {
+            let mut replacement_params = Vec::new();
+            free_params(replacement, &mut Vec::new(), &mut replacement_params);
+            if !replacement_params.contains(&a.as_str()) {
+                return Arc::new(Type::Forall(a.clone(), go(t)));
+            }
+
+            // Rename a to a name that is free in neither the body nor the replacement.
+            let mut t_params = Vec::new();
+            free_params(t, &mut Vec::new(), &mut t_params);
+            let mut fresh = format!("{}'", a);
+            while fresh == name
+                || replacement_params.contains(&fresh.as_str())
+                || t_params.contains(&fresh.as_str())
+            {
+                fresh.push('\'');
+            }
+            let t = substitute_param(t, a, &Arc::new(Type::Param(fresh.clone())));
+            Arc::new(Type::Forall(fresh, go(&t)))
+        }
+        Type::Param(a) if a == name => replacement.clone(),
+        Type::Bool | Type::Int | Type::Param(_) | Type::Unit | Type::Var(_) => ty.clone(),
     }
 }